@@ -0,0 +1,279 @@
+mod envelope;
+mod length_counter;
+mod mixer;
+mod noise;
+mod pulse;
+mod wave;
+
+use std::io;
+
+pub use self::mixer::{to_i16, Mixer, CPU_FREQUENCY, DEFAULT_SAMPLE_RATE};
+
+use self::noise::NoiseChannel;
+use self::pulse::PulseChannel;
+use self::wave::{WaveChannel, WAVE_RAM_LENGTH};
+use crate::model::Model;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const NR10: u16 = 0xFF10; // Channel 1 sweep
+pub const NR14: u16 = 0xFF14; // Channel 1 period high & control
+pub const NR21: u16 = 0xFF16; // Channel 2 length timer & duty cycle
+pub const NR24: u16 = 0xFF19; // Channel 2 period high & control
+pub const NR30: u16 = 0xFF1A; // Channel 3 DAC enable
+pub const NR34: u16 = 0xFF1E; // Channel 3 period high & control
+pub const NR41: u16 = 0xFF20; // Channel 4 length timer
+pub const NR44: u16 = 0xFF23; // Channel 4 control
+pub const NR50: u16 = 0xFF24; // Master volume & VIN panning
+pub const NR51: u16 = 0xFF25; // Sound panning
+pub const NR52: u16 = 0xFF26;
+/*
+NR52: Audio master control
+bit 7: All sound on/off (0=Off)
+bit 6-4: not used
+bit 3: Channel 4 on. READ-ONLY
+bit 2: Channel 3 on. READ-ONLY
+bit 1: Channel 2 on. READ-ONLY
+bit 0: Channel 1 on. READ-ONLY
+*/
+
+pub const WAVE_RAM: u16 = 0xFF30; // 0xFF30-0xFF3F
+const APU_END: u16 = 0xFF3F;
+
+const FRAME_SEQUENCER_DIV_BIT: u8 = 0x10; // Falling edge of DIV bit 4 clocks the sequencer at 512 Hz
+
+pub struct Apu {
+    pub enabled: bool,
+    pub frame_sequencer: u8, // Next step to run, 0-7
+    pub channel1: PulseChannel,
+    pub channel2: PulseChannel,
+    pub channel3: WaveChannel,
+    pub channel4: NoiseChannel,
+    pub mixer: Mixer,
+    cgb: bool,
+}
+
+impl Default for Apu {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
+}
+
+impl Apu {
+    pub fn new(model: Model) -> Self {
+        Apu {
+            enabled: false,
+            frame_sequencer: 0,
+            channel1: PulseChannel::new(true),
+            channel2: PulseChannel::new(false),
+            channel3: WaveChannel::new(model.is_cgb()),
+            channel4: NoiseChannel::new(),
+            mixer: Mixer::new(DEFAULT_SAMPLE_RATE),
+            cgb: model.is_cgb(),
+        }
+    }
+
+    /// Sound registers and wave RAM, 0xFF10-0xFF3F.
+    pub fn handles(address: u16) -> bool {
+        (NR10..=APU_END).contains(&address)
+    }
+
+    fn is_wave_ram(address: u16) -> bool {
+        (WAVE_RAM..WAVE_RAM + WAVE_RAM_LENGTH as u16).contains(&address)
+    }
+
+    /// `cycle`: M-cycles into the current instruction when the read happens. The channels are only
+    /// stepped once it is over.
+    pub fn read(&self, address: u16, cycle: u32) -> u8 {
+        match address {
+            NR10..=NR14 => self.channel1.read(address - NR10),
+            0xFF15..=NR24 => self.channel2.read(address - 0xFF15),
+            NR30..=NR34 => self.channel3.read(address - NR30),
+            NR41..=NR44 => self.channel4.read(address - NR41),
+            NR50 => self.mixer.nr50,
+            NR51 => self.mixer.nr51,
+            _ if Self::is_wave_ram(address) => self
+                .channel3
+                .read_wave_ram((address - WAVE_RAM) as usize, cycle * 4),
+            NR52 => {
+                0x70 | (self.enabled as u8) << 7
+                    | (self.channel4.enabled as u8) << 3
+                    | (self.channel3.enabled as u8) << 2
+                    | (self.channel2.enabled as u8) << 1
+                    | self.channel1.enabled as u8
+            }
+            _ => 0xFF,
+        }
+    }
+
+    /// `cycle`: M-cycles into the current instruction when the write happens, as for `read`.
+    pub fn write(&mut self, address: u16, value: u8, cycle: u32) {
+        if address == NR52 {
+            let enable = value & 0x80 != 0;
+            if enable && !self.enabled {
+                self.frame_sequencer = 0;
+            } else if !enable && self.enabled {
+                self.power_off();
+            }
+            self.enabled = enable;
+            return;
+        }
+        // Wave RAM isn't affected by the power switch
+        if Self::is_wave_ram(address) {
+            self.channel3
+                .write_wave_ram((address - WAVE_RAM) as usize, value, cycle * 4);
+            return;
+        }
+        if !self.enabled {
+            // DMG length counters keep working, and can be loaded, while the APU is off
+            if !self.cgb {
+                match address {
+                    0xFF11 => self.channel1.length.load(value & 0x3F),
+                    0xFF16 => self.channel2.length.load(value & 0x3F),
+                    0xFF1B => self.channel3.length.load(value),
+                    NR41 => self.channel4.length.load(value & 0x3F),
+                    _ => {}
+                }
+            }
+            return;
+        }
+
+        let extra_length_clock = self.frame_sequencer % 2 == 1;
+        match address {
+            NR10..=NR14 => self
+                .channel1
+                .write(address - NR10, value, extra_length_clock),
+            0xFF15..=NR24 => self
+                .channel2
+                .write(address - 0xFF15, value, extra_length_clock),
+            NR30..=NR34 => {
+                self.channel3
+                    .write(address - NR30, value, extra_length_clock, cycle * 4)
+            }
+            NR41..=NR44 => self
+                .channel4
+                .write(address - NR41, value, extra_length_clock),
+            NR50 => self.mixer.nr50 = value,
+            NR51 => self.mixer.nr51 = value,
+            _ => {}
+        }
+    }
+
+    // Every register but wave RAM is cleared
+    fn power_off(&mut self) {
+        let keep_length = !self.cgb;
+        self.channel1.power_off(keep_length);
+        self.channel2.power_off(keep_length);
+        self.channel3.power_off(keep_length);
+        self.channel4.power_off(keep_length);
+        self.mixer.nr50 = 0;
+        self.mixer.nr51 = 0;
+    }
+
+    /// Advances the channel timers by `cycles` M-cycles and mixes their output.
+    pub fn step(&mut self, cycles: u32) {
+        if self.enabled {
+            self.channel1.step(cycles * 4);
+            self.channel2.step(cycles * 4);
+            self.channel3.step(cycles * 4);
+            self.channel4.step(cycles * 4);
+        }
+        let dacs = [
+            dac(self.channel1.dac_enabled(), self.channel1.output()),
+            dac(self.channel2.dac_enabled(), self.channel2.output()),
+            dac(self.channel3.dac_enabled, self.channel3.output()),
+            dac(self.channel4.dac_enabled(), self.channel4.output()),
+        ];
+        self.mixer.mix(dacs, cycles * 4);
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.mixer.set_sample_rate(sample_rate);
+    }
+
+    /// Mixed output since the last call, interleaved left/right.
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.mixer.take_samples()
+    }
+
+    /// Same as `take_samples`, as signed 16-bit PCM.
+    pub fn take_samples_i16(&mut self) -> Vec<i16> {
+        self.mixer.take_samples_i16()
+    }
+
+    /// Called whenever DIV changes. A falling edge of DIV bit 4 clocks the frame sequencer.
+    pub fn div_changed(&mut self, old: u8, new: u8) {
+        if old & FRAME_SEQUENCER_DIV_BIT != 0 && new & FRAME_SEQUENCER_DIV_BIT == 0 {
+            self.clock_frame_sequencer();
+        }
+    }
+
+    /*
+    Step   Length Ctr  Vol Env     Sweep
+    ---------------------------------------
+    0      Clock       -           -
+    1      -           -           -
+    2      Clock       -           Clock
+    3      -           -           -
+    4      Clock       -           -
+    5      -           -           -
+    6      Clock       -           Clock
+    7      -           Clock       -
+    */
+    fn clock_frame_sequencer(&mut self) {
+        if !self.enabled {
+            return;
+        }
+
+        let step = self.frame_sequencer;
+        if step.is_multiple_of(2) {
+            self.channel1.clock_length();
+            self.channel2.clock_length();
+            self.channel3.clock_length();
+            self.channel4.clock_length();
+        }
+        if step == 2 || step == 6 {
+            self.channel1.clock_sweep();
+        }
+        if step == 7 {
+            self.channel1.clock_envelope();
+            self.channel2.clock_envelope();
+            self.channel4.clock_envelope();
+        }
+        self.frame_sequencer = (step + 1) % 8;
+    }
+}
+
+// Digital 0-15 to analog. A DAC that is off outputs nothing, so the high-pass filter settles at 0
+fn dac(enabled: bool, sample: u8) -> f32 {
+    if enabled {
+        1.0 - sample as f32 / 7.5
+    } else {
+        0.0
+    }
+}
+
+// Only the mixer's registers: its filters and buffers belong to the audio output, not the machine
+impl SaveState for Apu {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.frame_sequencer);
+        self.channel1.save_state(w);
+        self.channel2.save_state(w);
+        self.channel3.save_state(w);
+        self.channel4.save_state(w);
+        w.u8(self.mixer.nr50);
+        w.u8(self.mixer.nr51);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.bool()?;
+        self.frame_sequencer = r.u8()? % 8;
+        self.channel1.load_state(r)?;
+        self.channel2.load_state(r)?;
+        self.channel3.load_state(r)?;
+        self.channel4.load_state(r)?;
+        self.mixer.nr50 = r.u8()?;
+        self.mixer.nr51 = r.u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+/// Volume envelope (NRx2), clocked at 64 Hz by the frame sequencer.
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increase: bool,
+    pub period: u8,
+    pub volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            period: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.initial_volume << 4 | (self.increase as u8) << 3 | self.period
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.initial_volume = value >> 4;
+        self.increase = value & 0x08 != 0;
+        self.period = value & 0x07;
+    }
+
+    /// The DAC is powered while any of the upper 5 bits of NRx2 is set.
+    pub fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.reload_value();
+    }
+
+    pub fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
+        if self.timer == 0 {
+            self.timer = self.reload_value();
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+
+    // A period of 0 is treated as 8
+    fn reload_value(&self) -> u8 {
+        if self.period == 0 {
+            8
+        } else {
+            self.period
+        }
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for Envelope {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.initial_volume, self.period, self.volume, self.timer]);
+        w.bool(self.increase);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let [initial_volume, period, volume, timer]: [u8; 4] = r.bytes(4)?.try_into().unwrap();
+        (self.initial_volume, self.period) = (initial_volume, period);
+        (self.volume, self.timer) = (volume, timer);
+        self.increase = r.bool()?;
+        Ok(())
+    }
+}
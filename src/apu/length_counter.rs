@@ -0,0 +1,68 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+/// Length counter: silences a channel after `max - length` ticks of the 256 Hz frame sequencer clock.
+pub struct LengthCounter {
+    pub enabled: bool,
+    pub counter: u16,
+    max: u16, // 64, or 256 for the wave channel
+}
+
+impl LengthCounter {
+    pub fn new(max: u16) -> Self {
+        LengthCounter {
+            enabled: false,
+            counter: 0,
+            max,
+        }
+    }
+
+    pub fn load(&mut self, length: u8) {
+        self.counter = self.max - length as u16;
+    }
+
+    /// Returns true when the counter expires and the channel must be disabled.
+    pub fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter == 0;
+        }
+        false
+    }
+
+    /// Handles the enable bit and trigger of an NRx4 write. `extra_clock` is set when the next
+    /// frame sequencer step doesn't clock lengths: enabling the counter then clocks it once more.
+    /// Returns true when the channel must be disabled.
+    pub fn write_nrx4(&mut self, enable: bool, trigger: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        let mut disable = false;
+
+        if !was_enabled && enable && extra_clock && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+
+        if trigger && self.counter == 0 {
+            self.counter = self.max;
+            if enable && extra_clock {
+                self.counter -= 1;
+            }
+        }
+        disable
+    }
+}
+
+impl SaveState for LengthCounter {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.bool()?;
+        self.counter = r.u16()?;
+        Ok(())
+    }
+}
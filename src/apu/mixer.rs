@@ -0,0 +1,185 @@
+pub const CPU_FREQUENCY: u32 = 4_194_304; // T-cycles per second
+pub const DEFAULT_SAMPLE_RATE: u32 = 48_000;
+
+const MAX_BUFFERED_SECONDS: usize = 1; // Older samples are dropped if nobody pulls them
+
+/*
+NR50: Master volume & VIN panning
+bit 7: VIN to left output. Unused, no cartridge drives VIN
+bit 6-4: Left volume (0-7, 0 is still audible)
+bit 3: VIN to right output
+bit 2-0: Right volume
+
+NR51: Sound panning
+bit 7-4: Channel 4-1 to left output
+bit 3-0: Channel 4-1 to right output
+*/
+
+/// Mixes the channel DACs into stereo, resamples to the host rate and removes the DC offset.
+pub struct Mixer {
+    pub nr50: u8,
+    pub nr51: u8,
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    cycles: f64,   // T-cycles accumulated towards the next output sample
+    sum: [f64; 6], // Left, right and channels 1-4 levels integrated over those cycles
+    capacitor: [f32; 6],
+    charge_factor: f32,
+    samples: Vec<f32>, // Interleaved left/right, -1.0 to 1.0
+    capture_channels: bool,
+    channel_samples: [Vec<f32>; 4], // Each channel on its own, mono, before panning and volume
+}
+
+impl Mixer {
+    pub fn new(sample_rate: u32) -> Self {
+        let mut mixer = Mixer {
+            nr50: 0,
+            nr51: 0,
+            sample_rate,
+            cycles_per_sample: 0.0,
+            cycles: 0.0,
+            sum: [0.0; 6],
+            capacitor: [0.0; 6],
+            charge_factor: 0.0,
+            samples: Vec::new(),
+            capture_channels: false,
+            channel_samples: Default::default(),
+        };
+        mixer.set_sample_rate(sample_rate);
+        mixer
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.cycles_per_sample = CPU_FREQUENCY as f64 / sample_rate as f64;
+        // High-pass filter of the output capacitor, 0.999958 per T-cycle on DMG
+        self.charge_factor = 0.999958f32.powf(self.cycles_per_sample as f32);
+    }
+
+    /// Adds `cycles` T-cycles of output. `dacs` are the analog channel outputs, -1.0 to 1.0.
+    pub fn mix(&mut self, dacs: [f32; 4], cycles: u32) {
+        let mut level = [0.0f32; 2];
+        for (channel, dac) in dacs.iter().enumerate() {
+            if self.nr51 & (1 << (channel + 4)) != 0 {
+                level[0] += dac;
+            }
+            if self.nr51 & (1 << channel) != 0 {
+                level[1] += dac;
+            }
+        }
+        let volume = [((self.nr50 >> 4) & 0x07) + 1, (self.nr50 & 0x07) + 1];
+
+        let mut levels = [0.0f32; 6];
+        for side in 0..2 {
+            // 4 channels at full master volume map to -1.0..1.0
+            levels[side] = level[side] * volume[side] as f32 / 32.0;
+        }
+        levels[2..].copy_from_slice(&dacs);
+        let outputs = if self.capture_channels { 6 } else { 2 };
+
+        let mut cycles = cycles as f64;
+        while cycles > 0.0 {
+            let elapsed = cycles.min(self.cycles_per_sample - self.cycles);
+            for (sum, level) in self.sum.iter_mut().zip(levels).take(outputs) {
+                *sum += level as f64 * elapsed;
+            }
+            self.cycles += elapsed;
+            cycles -= elapsed;
+
+            if self.cycles >= self.cycles_per_sample - 1e-9 {
+                for output in 0..outputs {
+                    let sample = self.high_pass(output, (self.sum[output] / self.cycles) as f32);
+                    match output {
+                        0 | 1 => self.samples.push(sample),
+                        channel => self.channel_samples[channel - 2].push(sample),
+                    }
+                }
+                self.sum = [0.0; 6];
+                self.cycles = 0.0;
+            }
+        }
+
+        let max = self.sample_rate as usize * MAX_BUFFERED_SECONDS;
+        if self.samples.len() > max * 2 {
+            self.samples.drain(..self.samples.len() - max * 2);
+        }
+        for samples in self.channel_samples.iter_mut() {
+            if samples.len() > max {
+                samples.drain(..samples.len() - max);
+            }
+        }
+    }
+
+    // Output capacitor: removes the DC offset of the DACs
+    fn high_pass(&mut self, output: usize, input: f32) -> f32 {
+        let result = input - self.capacitor[output];
+        self.capacitor[output] = input - result * self.charge_factor;
+        result
+    }
+
+    /// Also keeps each channel's output apart, see `take_channel_samples`.
+    pub fn set_channel_capture(&mut self, enabled: bool) {
+        self.capture_channels = enabled;
+        if !enabled {
+            self.channel_samples = Default::default();
+        }
+    }
+
+    /// Mono output of channels 1-4 since the last call, at the same rate as `take_samples`.
+    pub fn take_channel_samples(&mut self) -> [Vec<f32>; 4] {
+        std::mem::take(&mut self.channel_samples)
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    pub fn take_samples_i16(&mut self) -> Vec<i16> {
+        self.take_samples().into_iter().map(to_i16).collect()
+    }
+
+    pub fn buffered(&self) -> usize {
+        self.samples.len() / 2
+    }
+}
+
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One output sample from constant DAC levels
+    fn sample(nr50: u8, nr51: u8, dacs: [f32; 4]) -> [f32; 2] {
+        let mut mixer = Mixer::new(CPU_FREQUENCY / 64);
+        (mixer.nr50, mixer.nr51) = (nr50, nr51);
+        mixer.mix(dacs, 64);
+        mixer.take_samples().try_into().unwrap()
+    }
+
+    #[test]
+    fn nr51_routes_each_channel_to_its_sides() {
+        let dacs = [1.0, 0.5, -0.5, -1.0];
+        assert_eq!(sample(0x77, 0x00, dacs), [0.0, 0.0]);
+        assert_eq!(sample(0x77, 0x10, dacs), [0.25, 0.0]); // Channel 1 left
+        assert_eq!(sample(0x77, 0x02, dacs), [0.0, 0.125]); // Channel 2 right
+        assert_eq!(sample(0x77, 0x84, dacs), [-0.25, -0.125]); // 4 left, 3 right
+        assert_eq!(sample(0x77, 0xFF, dacs), [0.0, 0.0]);
+        assert_eq!(sample(0x77, 0x33, dacs), [0.375, 0.375]);
+    }
+
+    #[test]
+    fn nr50_scales_each_side() {
+        let dacs = [1.0, 1.0, 1.0, 1.0];
+        assert_eq!(sample(0x77, 0xFF, dacs), [1.0, 1.0]);
+        assert_eq!(sample(0x70, 0xFF, dacs), [1.0, 0.125]); // Volume 0 is still 1/8
+        assert_eq!(sample(0x13, 0xFF, dacs), [0.25, 0.5]);
+        assert_eq!(sample(0x88, 0xFF, dacs), [0.125, 0.125]); // VIN bits don't count
+    }
+}
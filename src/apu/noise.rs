@@ -0,0 +1,247 @@
+use std::io;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+const DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112]; // T-cycles, by NR43 divisor code
+
+/// Noise channel (channel 4): pseudo-random output from a linear feedback shift register.
+pub struct NoiseChannel {
+    pub enabled: bool,
+    pub clock_shift: u8,
+    pub width_7: bool, // 7-bit LFSR instead of 15-bit, gives a more periodic tone
+    pub divisor_code: u8,
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    lfsr: u16,
+    timer: u32, // T-cycles until the next LFSR shift
+}
+
+impl NoiseChannel {
+    pub fn new() -> Self {
+        NoiseChannel {
+            enabled: false,
+            clock_shift: 0,
+            width_7: false,
+            divisor_code: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            lfsr: 0x7FFF,
+            timer: 0,
+        }
+    }
+
+    // Register index: 0 = NR41 .. 3 = NR44
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => 0xFF,
+            1 => self.envelope.read(),
+            2 => self.clock_shift << 4 | (self.width_7 as u8) << 3 | self.divisor_code,
+            3 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    /// `extra_length_clock`: the next frame sequencer step doesn't clock lengths.
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => self.length.load(value & 0x3F),
+            1 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            2 => {
+                self.clock_shift = value >> 4;
+                self.width_7 = value & 0x08 != 0;
+                self.divisor_code = value & 0x07;
+            }
+            3 => {
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_nrx4(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.lfsr = 0x7FFF;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn period(&self) -> u32 {
+        DIVISORS[self.divisor_code as usize] << self.clock_shift
+    }
+
+    /// Advances the frequency timer by `cycles` T-cycles.
+    pub fn step(&mut self, cycles: u32) {
+        // Shifts 14 and 15 stop the LFSR clock
+        if !self.enabled || self.clock_shift >= 14 {
+            return;
+        }
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer == 0 {
+                self.timer = self.period();
+            }
+            let elapsed = cycles.min(self.timer);
+            self.timer -= elapsed;
+            cycles -= elapsed;
+            if self.timer == 0 {
+                self.shift_lfsr();
+            }
+        }
+    }
+
+    fn shift_lfsr(&mut self) {
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.width_7 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Digital output, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Clears every register, as when the APU is powered off. DMG keeps the length counter.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let counter = self.length.counter;
+        *self = NoiseChannel::new();
+        if keep_length {
+            self.length.counter = counter;
+        }
+    }
+}
+
+impl Default for NoiseChannel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SaveState for NoiseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.clock_shift);
+        w.bool(self.width_7);
+        w.u8(self.divisor_code);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        w.u16(self.lfsr);
+        w.u32(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.bool()?;
+        self.clock_shift = r.u8()? & 0x0F;
+        self.width_7 = r.bool()?;
+        self.divisor_code = r.u8()? & 0x07;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        self.lfsr = r.u16()?;
+        self.timer = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Triggered at full volume with the given NR43
+    fn triggered(nr43: u8) -> NoiseChannel {
+        let mut channel = NoiseChannel::new();
+        channel.write(1, 0xF0, false);
+        channel.write(2, nr43, false);
+        channel.write(3, 0x80, false);
+        channel
+    }
+
+    // Shifts until the LFSR comes back to the state it had after `warm_up` shifts
+    fn lfsr_period(channel: &mut NoiseChannel, warm_up: usize) -> usize {
+        (0..warm_up).for_each(|_| channel.shift_lfsr());
+        let start = channel.lfsr;
+        (1..=0x8000)
+            .find(|_| {
+                channel.shift_lfsr();
+                channel.lfsr == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn lfsr_15_bit_runs_through_every_state() {
+        let mut channel = triggered(0x00);
+        assert_eq!(lfsr_period(&mut channel, 0), 0x7FFF);
+    }
+
+    #[test]
+    fn lfsr_7_bit_repeats_every_127_shifts() {
+        // Bits above 6 take a few shifts to follow the short loop
+        let mut channel = triggered(0x08);
+        assert_eq!(lfsr_period(&mut channel, 8), 127);
+    }
+
+    #[test]
+    fn lfsr_shifts_at_the_nr43_rate() {
+        let mut channel = triggered(0x21); // Divisor 16, shift 2: every 64 T-cycles
+        channel.step(63);
+        assert_eq!(channel.lfsr, 0x7FFF);
+        assert_eq!(channel.output(), 0);
+        channel.step(1);
+        assert_eq!(channel.lfsr, 0x3FFF);
+
+        // Output is the inverted bit 0
+        (0..14).for_each(|_| channel.shift_lfsr());
+        assert_eq!(channel.lfsr & 1, 0);
+        assert_eq!(channel.output(), 15);
+
+        let mut channel = triggered(0xE0); // Shift 14 stops the clock
+        channel.step(1 << 20);
+        assert_eq!(channel.lfsr, 0x7FFF);
+    }
+
+    #[test]
+    fn load_state_keeps_the_clock_shift_to_4_bits() {
+        let mut w = StateWriter::new();
+        triggered(0x00).save_state(&mut w);
+        let mut data = w.into_bytes();
+        data[1] = 0xFF; // After enabled
+
+        let mut channel = NoiseChannel::new();
+        channel.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(channel.read(2), 0xF0);
+    }
+}
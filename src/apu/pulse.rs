@@ -0,0 +1,383 @@
+use std::io;
+
+use super::envelope::Envelope;
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+const DUTY_PATTERNS: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1], // 12.5%
+    [1, 0, 0, 0, 0, 0, 0, 1], // 25%
+    [1, 0, 0, 0, 0, 1, 1, 1], // 50%
+    [0, 1, 1, 1, 1, 1, 1, 0], // 75%
+];
+
+/// Frequency sweep of channel 1 (NR10), clocked at 128 Hz by the frame sequencer.
+pub struct Sweep {
+    pub period: u8,
+    pub negate: bool,
+    pub shift: u8,
+    pub enabled: bool,
+    shadow: u16,
+    timer: u8,
+    negate_used: bool, // A subtraction was computed since the last trigger
+}
+
+impl Sweep {
+    fn new() -> Self {
+        Sweep {
+            period: 0,
+            negate: false,
+            shift: 0,
+            enabled: false,
+            shadow: 0,
+            timer: 0,
+            negate_used: false,
+        }
+    }
+
+    fn reload_timer(&mut self) {
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+/// Square wave channel: channel 1 (with sweep) and channel 2.
+pub struct PulseChannel {
+    pub enabled: bool,
+    pub duty: u8,
+    pub frequency: u16, // 11 bits, NRx3 + low 3 bits of NRx4
+    pub length: LengthCounter,
+    pub envelope: Envelope,
+    pub sweep: Option<Sweep>,
+    duty_position: usize,
+    timer: u32, // T-cycles until the next duty step
+}
+
+impl PulseChannel {
+    pub fn new(with_sweep: bool) -> Self {
+        PulseChannel {
+            enabled: false,
+            duty: 0,
+            frequency: 0,
+            length: LengthCounter::new(64),
+            envelope: Envelope::new(),
+            sweep: if with_sweep { Some(Sweep::new()) } else { None },
+            duty_position: 0,
+            timer: 0,
+        }
+    }
+
+    // Register index: 0 = NRx0 (sweep) .. 4 = NRx4
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => match &self.sweep {
+                Some(sweep) => 0x80 | sweep.period << 4 | (sweep.negate as u8) << 3 | sweep.shift,
+                None => 0xFF,
+            },
+            1 => self.duty << 6 | 0x3F,
+            2 => self.envelope.read(),
+            3 => 0xFF,
+            4 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    /// `extra_length_clock`: the next frame sequencer step doesn't clock lengths.
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool) {
+        match register {
+            0 => {
+                if let Some(sweep) = self.sweep.as_mut() {
+                    sweep.period = (value >> 4) & 0x07;
+                    sweep.negate = value & 0x08 != 0;
+                    sweep.shift = value & 0x07;
+                    // Leaving negate mode after a subtraction disables the channel
+                    if !sweep.negate && sweep.negate_used {
+                        self.enabled = false;
+                    }
+                }
+            }
+            1 => {
+                self.duty = value >> 6;
+                self.length.load(value & 0x3F);
+            }
+            2 => {
+                self.envelope.write(value);
+                if !self.envelope.dac_enabled() {
+                    self.enabled = false;
+                }
+            }
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_nrx4(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = (2048 - self.frequency as u32) * 4;
+        self.envelope.trigger();
+
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.frequency;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period != 0 || sweep.shift != 0;
+            sweep.negate_used = false;
+            if sweep.shift != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Advances the frequency timer by `cycles` T-cycles.
+    pub fn step(&mut self, cycles: u32) {
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if self.timer == 0 {
+                self.timer = (2048 - self.frequency as u32) * 4;
+            }
+            let elapsed = cycles.min(self.timer);
+            self.timer -= elapsed;
+            cycles -= elapsed;
+            if self.timer == 0 {
+                self.duty_position = (self.duty_position + 1) % 8;
+            }
+        }
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        if sweep.timer > 0 {
+            sweep.timer -= 1;
+        }
+        if sweep.timer != 0 {
+            return;
+        }
+        sweep.reload_timer();
+
+        if sweep.enabled && sweep.period != 0 {
+            let frequency = sweep.next_frequency();
+            if frequency > 2047 {
+                self.enabled = false;
+            } else if sweep.shift != 0 {
+                sweep.shadow = frequency;
+                self.frequency = frequency;
+                // Overflow is checked again with the new frequency
+                if sweep.next_frequency() > 2047 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    /// Digital output, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        DUTY_PATTERNS[self.duty as usize][self.duty_position] * self.envelope.volume
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    /// Clears every register, as when the APU is powered off. DMG keeps the length counter.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let counter = self.length.counter;
+        *self = PulseChannel::new(self.sweep.is_some());
+        if keep_length {
+            self.length.counter = counter;
+        }
+    }
+}
+
+impl SaveState for Sweep {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&[self.period, self.shift, self.timer]);
+        w.bool(self.negate);
+        w.bool(self.enabled);
+        w.bool(self.negate_used);
+        w.u16(self.shadow);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let [period, shift, timer]: [u8; 3] = r.bytes(3)?.try_into().unwrap();
+        (self.period, self.shift, self.timer) = (period & 0x07, shift & 0x07, timer);
+        self.negate = r.bool()?;
+        self.enabled = r.bool()?;
+        self.negate_used = r.bool()?;
+        self.shadow = r.u16()?;
+        Ok(())
+    }
+}
+
+// Whether the channel has a sweep is fixed, channel 1 always does
+impl SaveState for PulseChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u8(self.duty);
+        w.u16(self.frequency);
+        self.length.save_state(w);
+        self.envelope.save_state(w);
+        if let Some(sweep) = &self.sweep {
+            sweep.save_state(w);
+        }
+        w.u8(self.duty_position as u8);
+        w.u32(self.timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.bool()?;
+        self.duty = r.u8()? & 0x03;
+        self.frequency = r.u16()? & 0x7FF;
+        self.length.load_state(r)?;
+        self.envelope.load_state(r)?;
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.load_state(r)?;
+        }
+        self.duty_position = (r.u8()? & 0x07) as usize;
+        self.timer = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Channel 1 triggered at full volume with the given NR10 and frequency
+    fn triggered(nr10: u8, frequency: u16) -> PulseChannel {
+        let mut channel = PulseChannel::new(true);
+        channel.write(0, nr10, false);
+        channel.write(2, 0xF0, false);
+        channel.write(3, frequency as u8, false);
+        channel.write(4, 0x80 | (frequency >> 8) as u8, false);
+        channel
+    }
+
+    #[test]
+    fn sweep_adds_the_shifted_frequency() {
+        let mut channel = triggered(0x11, 0x100); // Period 1, shift 1
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x180);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x240);
+        assert!(channel.enabled);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_the_channel() {
+        // The overflow check on trigger already fails
+        let channel = triggered(0x11, 0x600);
+        assert!(!channel.enabled);
+
+        // 0x500 -> 0x780 passes, but the check with the new frequency overflows
+        let mut channel = triggered(0x11, 0x500);
+        assert!(channel.enabled);
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x780);
+        assert!(!channel.enabled);
+    }
+
+    #[test]
+    fn leaving_negate_mode_after_a_subtraction_disables_the_channel() {
+        let mut channel = triggered(0x19, 0x400); // Period 1, negate, shift 1
+        channel.clock_sweep();
+        assert_eq!(channel.frequency, 0x200);
+        assert!(channel.enabled);
+        channel.write(0, 0x11, false);
+        assert!(!channel.enabled);
+    }
+
+    #[test]
+    fn envelope_steps_the_volume_every_period() {
+        let mut channel = triggered(0, 0);
+        channel.write(2, 0x82, false); // Volume 8, decreasing, period 2
+        channel.write(4, 0x80, false);
+        for volume in [8, 7, 7, 6] {
+            channel.clock_envelope();
+            assert_eq!(channel.envelope.volume, volume);
+        }
+
+        channel.write(2, 0xE9, false); // Volume 14, increasing, period 1
+        channel.write(4, 0x80, false);
+        for volume in [15, 15] {
+            channel.clock_envelope();
+            assert_eq!(channel.envelope.volume, volume);
+        }
+    }
+
+    #[test]
+    fn length_counter_silences_the_channel() {
+        let mut channel = triggered(0, 0);
+        channel.write(1, 0x3D, false); // 64 - 61 = 3 ticks
+        channel.write(4, 0x40, false);
+        channel.clock_length();
+        channel.clock_length();
+        assert!(channel.enabled);
+        channel.clock_length();
+        assert!(!channel.enabled);
+
+        // Without the enable bit the counter doesn't run
+        let mut channel = triggered(0, 0);
+        channel.write(1, 0x3F, false);
+        channel.clock_length();
+        assert!(channel.enabled);
+    }
+
+    #[test]
+    fn load_state_masks_register_fields() {
+        let mut w = StateWriter::new();
+        w.bool(true);
+        w.u8(0xFF);
+        w.u16(0xFFFF);
+        LengthCounter::new(64).save_state(&mut w);
+        Envelope::new().save_state(&mut w);
+        w.bytes(&[0xFF, 0xFF, 0]);
+        w.bytes(&[0, 0, 0]);
+        w.u16(0);
+        w.u8(0xFF);
+        w.u32(0);
+        let data = w.into_bytes();
+
+        let mut channel = PulseChannel::new(true);
+        channel.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!((channel.duty, channel.frequency), (0x03, 0x7FF));
+        let sweep = channel.sweep.as_ref().unwrap();
+        assert_eq!((sweep.period, sweep.shift), (0x07, 0x07));
+        assert_eq!(channel.read(0), 0xF7);
+    }
+}
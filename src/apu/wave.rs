@@ -0,0 +1,320 @@
+use std::io;
+
+use super::length_counter::LengthCounter;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const WAVE_RAM_LENGTH: usize = 16; // 32 4-bit samples, high nibble first
+
+// T-cycles after a sample fetch during which the CPU still reaches wave RAM on DMG
+const DMG_ACCESS_WINDOW: u32 = 2;
+// T-cycles before a sample fetch during which a DMG retrigger corrupts wave RAM: the fetch lands
+// on the same 2 MHz APU tick as the write
+const DMG_CORRUPTION_WINDOW: u32 = 2;
+// Triggering delays the first sample fetch by this many T-cycles
+const TRIGGER_DELAY: u32 = 6;
+
+/// Wave channel (channel 3): plays the 32 samples of wave RAM.
+pub struct WaveChannel {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    pub output_level: u8, // 0 = mute, 1 = 100%, 2 = 50%, 3 = 25%
+    pub frequency: u16,
+    pub length: LengthCounter,
+    pub wave_ram: [u8; WAVE_RAM_LENGTH],
+    cgb: bool,
+    position: usize,  // Sample index, 0-31
+    sample: u8,       // Last fetched sample, the one being played
+    timer: u32,       // T-cycles until the next sample fetch
+    since_fetch: u32, // T-cycles since wave RAM was last read by the channel
+}
+
+impl WaveChannel {
+    pub fn new(cgb: bool) -> Self {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            output_level: 0,
+            frequency: 0,
+            length: LengthCounter::new(256),
+            wave_ram: [0; WAVE_RAM_LENGTH],
+            cgb,
+            position: 0,
+            sample: 0,
+            timer: 0,
+            since_fetch: u32::MAX,
+        }
+    }
+
+    // Register index: 0 = NR30 .. 4 = NR34
+    pub fn read(&self, register: u16) -> u8 {
+        match register {
+            0 => 0x7F | (self.dac_enabled as u8) << 7,
+            1 => 0xFF,
+            2 => 0x9F | self.output_level << 5,
+            3 => 0xFF,
+            4 => 0xBF | (self.length.enabled as u8) << 6,
+            _ => 0xFF,
+        }
+    }
+
+    /// `extra_length_clock`: the next frame sequencer step doesn't clock lengths. `delay`: T-cycles
+    /// between the last `step` and the write, as for `write_wave_ram`.
+    pub fn write(&mut self, register: u16, value: u8, extra_length_clock: bool, delay: u32) {
+        match register {
+            0 => {
+                self.dac_enabled = value & 0x80 != 0;
+                if !self.dac_enabled {
+                    self.enabled = false;
+                }
+            }
+            1 => self.length.load(value),
+            2 => self.output_level = (value >> 5) & 0x03,
+            3 => self.frequency = (self.frequency & 0x700) | value as u16,
+            4 => {
+                self.frequency = (self.frequency & 0xFF) | ((value as u16 & 0x07) << 8);
+                let trigger = value & 0x80 != 0;
+                if self
+                    .length
+                    .write_nrx4(value & 0x40 != 0, trigger, extra_length_clock)
+                {
+                    self.enabled = false;
+                }
+                if trigger {
+                    self.trigger(delay);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+    While the channel plays, wave RAM accesses go to the byte the channel is reading.
+    On CGB that always works. On DMG it only works right as the channel fetches a sample:
+    any other time reads return 0xFF and writes are ignored.
+
+    The channel is stepped once the instruction is over, so accesses take `delay`: the T-cycles
+    from the last `step` to the M-cycle the access happens in.
+    */
+    pub fn read_wave_ram(&self, index: usize, delay: u32) -> u8 {
+        if !self.enabled {
+            return self.wave_ram[index];
+        }
+        let (position, _, since_fetch) = self.ahead(delay);
+        if self.cgb || since_fetch < DMG_ACCESS_WINDOW {
+            self.wave_ram[position / 2]
+        } else {
+            0xFF
+        }
+    }
+
+    pub fn write_wave_ram(&mut self, index: usize, value: u8, delay: u32) {
+        if !self.enabled {
+            self.wave_ram[index] = value;
+            return;
+        }
+        let (position, _, since_fetch) = self.ahead(delay);
+        if self.cgb || since_fetch < DMG_ACCESS_WINDOW {
+            self.wave_ram[position / 2] = value;
+        }
+    }
+
+    fn trigger(&mut self, delay: u32) {
+        // DMG: retriggering just as a sample is fetched corrupts the first bytes of wave RAM. Only
+        // the first one if the fetch was from them, otherwise with the 4-byte block it was from
+        if self.enabled && !self.cgb {
+            let (position, timer, _) = self.ahead(delay);
+            if timer > 0 && timer <= DMG_CORRUPTION_WINDOW {
+                let index = ((position + 1) % 32) / 2;
+                if index < 4 {
+                    self.wave_ram[0] = self.wave_ram[index];
+                } else {
+                    let block = index & !0x03;
+                    self.wave_ram.copy_within(block..block + 4, 0);
+                }
+            }
+        }
+
+        self.enabled = self.dac_enabled;
+        self.position = 0;
+        // The next `step` also covers the `delay` before the trigger
+        self.timer = self.period() + TRIGGER_DELAY + delay;
+        self.since_fetch = u32::MAX;
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.frequency as u32) * 2
+    }
+
+    /// Advances the frequency timer by `cycles` T-cycles.
+    pub fn step(&mut self, cycles: u32) {
+        if !self.enabled {
+            return;
+        }
+        let (position, timer, since_fetch) = self.ahead(cycles);
+        // Only the last sample fetched is played
+        if since_fetch < cycles {
+            let byte = self.wave_ram[position / 2];
+            self.sample = if position.is_multiple_of(2) {
+                byte >> 4
+            } else {
+                byte & 0x0F
+            };
+        }
+        (self.position, self.timer, self.since_fetch) = (position, timer, since_fetch);
+    }
+
+    // Position, timer and T-cycles since the last fetch as they will be `cycles` T-cycles from now
+    fn ahead(&self, cycles: u32) -> (usize, u32, u32) {
+        let (mut position, mut timer, mut since_fetch) =
+            (self.position, self.timer, self.since_fetch);
+        let mut cycles = cycles;
+        while cycles > 0 {
+            if timer == 0 {
+                timer = self.period();
+            }
+            let elapsed = cycles.min(timer);
+            timer -= elapsed;
+            cycles -= elapsed;
+            since_fetch = since_fetch.saturating_add(elapsed);
+            if timer == 0 {
+                position = (position + 1) % 32;
+                since_fetch = 0;
+            }
+        }
+        (position, timer, since_fetch)
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    /// Digital output, 0-15.
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.output_level == 0 {
+            return 0;
+        }
+        self.sample >> (self.output_level - 1)
+    }
+
+    /// Clears every register, as when the APU is powered off. Wave RAM is kept, and so is the
+    /// length counter on DMG.
+    pub fn power_off(&mut self, keep_length: bool) {
+        let counter = self.length.counter;
+        *self = WaveChannel {
+            wave_ram: self.wave_ram,
+            ..WaveChannel::new(self.cgb)
+        };
+        if keep_length {
+            self.length.counter = counter;
+        }
+    }
+}
+
+impl SaveState for WaveChannel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.bool(self.dac_enabled);
+        w.u8(self.output_level);
+        w.u16(self.frequency);
+        self.length.save_state(w);
+        w.bytes(&self.wave_ram);
+        w.u8(self.position as u8);
+        w.u8(self.sample);
+        w.u32(self.timer);
+        w.u32(self.since_fetch);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.enabled = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.output_level = r.u8()? & 0x03;
+        self.frequency = r.u16()? & 0x7FF;
+        self.length.load_state(r)?;
+        r.read_into(&mut self.wave_ram)?;
+        self.position = (r.u8()? % 32) as usize;
+        self.sample = r.u8()?;
+        self.timer = r.u32()?;
+        self.since_fetch = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WAVE: [u8; WAVE_RAM_LENGTH] = [
+        0x00, 0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xAA, 0xBB, 0xCC, 0xDD, 0xEE,
+        0xFF,
+    ];
+
+    // DMG channel that just fetched sample 1, with a fetch every 4 T-cycles
+    fn playing() -> WaveChannel {
+        let mut channel = WaveChannel::new(false);
+        channel.wave_ram = WAVE;
+        channel.dac_enabled = true;
+        channel.frequency = 0x7FE;
+        channel.trigger(0);
+        channel.step(4 + TRIGGER_DELAY);
+        assert_eq!((channel.position, channel.timer), (1, 0));
+        channel
+    }
+
+    #[test]
+    fn retrigger_as_a_fetch_is_due_corrupts_wave_ram() {
+        let mut channel = playing();
+        channel.step(2);
+        channel.trigger(0);
+        assert_eq!(channel.wave_ram[..2], [0x11, 0x11]);
+        assert_eq!(channel.wave_ram[2..], WAVE[2..]);
+
+        // Due 2 T-cycles into the instruction
+        let mut channel = playing();
+        channel.trigger(2);
+        assert_eq!(channel.wave_ram[0], 0x11);
+    }
+
+    #[test]
+    fn retrigger_copies_the_block_being_fetched() {
+        let mut channel = playing();
+        channel.step(4 * 8 + 2); // Sample 9 is next, from byte 4
+        channel.trigger(0);
+        assert_eq!(channel.wave_ram[..4], WAVE[4..8]);
+        assert_eq!(channel.wave_ram[4..], WAVE[4..]);
+    }
+
+    #[test]
+    fn retrigger_between_fetches_leaves_wave_ram_alone() {
+        let mut channel = playing();
+        channel.trigger(0); // Right after a fetch
+        assert_eq!(channel.wave_ram, WAVE);
+
+        let mut channel = playing();
+        channel.step(1);
+        channel.trigger(0); // 3 T-cycles early
+        assert_eq!(channel.wave_ram, WAVE);
+    }
+
+    #[test]
+    fn dmg_wave_ram_is_only_reachable_right_after_a_fetch() {
+        let channel = playing();
+        assert_eq!(channel.read_wave_ram(5, 0), 0x00);
+        assert_eq!(channel.read_wave_ram(5, 2), 0xFF);
+        assert_eq!(channel.read_wave_ram(5, 4), 0x11); // Sample 2 was just fetched
+    }
+
+    #[test]
+    fn load_state_keeps_the_frequency_to_11_bits() {
+        let mut w = StateWriter::new();
+        playing().save_state(&mut w);
+        let mut data = w.into_bytes();
+        data[3..5].copy_from_slice(&0xFFFFu16.to_le_bytes()); // After enabled, DAC and output level
+
+        let mut channel = WaveChannel::new(false);
+        channel.load_state(&mut StateReader::new(&data)).unwrap();
+        assert_eq!(channel.frequency, 0x7FF);
+        channel.step(4);
+    }
+}
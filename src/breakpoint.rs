@@ -0,0 +1,429 @@
+use std::fmt;
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use crate::cpu::CPU;
+
+/*
+Breakpoint conditions compare registers with values:
+    a == $10
+    hl >= C000 && zf == 1
+    b != 0 || c < 8
+Registers: a f b c d e h l af bc de hl sp pc, the flags zf nf hf cf and ime (0 or 1).
+Operators: == != < <= > >=. Values are hex, as addresses are everywhere else ($10, 0x10, 10).
+&& binds tighter than ||. There are no parentheses.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)] // IME, as in the rest of the CPU code
+enum Register {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+    PC,
+    ZF,
+    NF,
+    HF,
+    CF,
+    IME,
+}
+
+const REGISTERS: [(&str, Register); 19] = [
+    ("a", Register::A),
+    ("f", Register::F),
+    ("b", Register::B),
+    ("c", Register::C),
+    ("d", Register::D),
+    ("e", Register::E),
+    ("h", Register::H),
+    ("l", Register::L),
+    ("af", Register::AF),
+    ("bc", Register::BC),
+    ("de", Register::DE),
+    ("hl", Register::HL),
+    ("sp", Register::SP),
+    ("pc", Register::PC),
+    ("zf", Register::ZF),
+    ("nf", Register::NF),
+    ("hf", Register::HF),
+    ("cf", Register::CF),
+    ("ime", Register::IME),
+];
+
+impl Register {
+    fn value(self, cpu: &CPU) -> u16 {
+        let r = &cpu.registers;
+        match self {
+            Register::A => r.a as u16,
+            Register::F => r.f as u16,
+            Register::B => r.b as u16,
+            Register::C => r.c as u16,
+            Register::D => r.d as u16,
+            Register::E => r.e as u16,
+            Register::H => r.h as u16,
+            Register::L => r.l as u16,
+            Register::AF => cpu.get_af(),
+            Register::BC => cpu.get_bc(),
+            Register::DE => cpu.get_de(),
+            Register::HL => cpu.get_hl(),
+            Register::SP => r.sp,
+            Register::PC => r.pc,
+            Register::ZF => cpu.get_zf() as u16,
+            Register::NF => cpu.get_nf() as u16,
+            Register::HF => cpu.get_hf() as u16,
+            Register::CF => cpu.get_cf() as u16,
+            Register::IME => cpu.ime as u16,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        REGISTERS.iter().find(|(_, r)| *r == self).unwrap().0
+    }
+}
+
+const OPERATORS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Comparison {
+    register: Register,
+    operator: &'static str,
+    value: u16,
+}
+
+impl Comparison {
+    fn eval(&self, cpu: &CPU) -> bool {
+        let register = self.register.value(cpu);
+        match self.operator {
+            "==" => register == self.value,
+            "!=" => register != self.value,
+            "<=" => register <= self.value,
+            ">=" => register >= self.value,
+            "<" => register < self.value,
+            _ => register > self.value,
+        }
+    }
+}
+
+impl FromStr for Comparison {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let (position, operator) = OPERATORS
+            .iter()
+            .filter_map(|op| text.find(op).map(|i| (i, *op)))
+            .min_by_key(|(i, op)| (*i, std::cmp::Reverse(op.len())))
+            .ok_or_else(|| format!("'{}' is not a comparison", text.trim()))?;
+        let name = text[..position].trim().to_ascii_lowercase();
+        let register = REGISTERS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, r)| *r)
+            .ok_or_else(|| format!("unknown register '{}'", name))?;
+        let value = text[position + operator.len()..].trim();
+        Ok(Comparison {
+            register,
+            operator,
+            value: parse_hex(value)?,
+        })
+    }
+}
+
+/// Register condition on a breakpoint, see the top of this file for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    any: Vec<Vec<Comparison>>, // Any of these groups, all of the comparisons in the group
+}
+
+impl Condition {
+    pub fn eval(&self, cpu: &CPU) -> bool {
+        self.any
+            .iter()
+            .any(|all| all.iter().all(|comparison| comparison.eval(cpu)))
+    }
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let any = text
+            .split("||")
+            .map(|group| group.split("&&").map(str::parse).collect())
+            .collect::<Result<_, String>>()?;
+        Ok(Condition { any })
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, all) in self.any.iter().enumerate() {
+            if i > 0 {
+                write!(f, " || ")?;
+            }
+            for (j, comparison) in all.iter().enumerate() {
+                if j > 0 {
+                    write!(f, " && ")?;
+                }
+                write!(
+                    f,
+                    "{} {} ${:X}",
+                    comparison.register.name(),
+                    comparison.operator,
+                    comparison.value
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    pub condition: Option<Condition>,
+}
+
+impl Breakpoint {
+    pub fn new(address: u16, condition: Option<Condition>) -> Self {
+        Breakpoint { address, condition }
+    }
+
+    /// True if the CPU stops here, with PC already checked against `address`.
+    pub fn hit(&self, cpu: &CPU) -> bool {
+        self.condition.as_ref().is_none_or(|c| c.eval(cpu))
+    }
+}
+
+/// What a watchpoint looks for. Any combination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub read: bool,
+    pub write: bool,
+    pub execute: bool,
+}
+
+impl Access {
+    pub const READ: Access = Access {
+        read: true,
+        write: false,
+        execute: false,
+    };
+    pub const WRITE: Access = Access {
+        read: false,
+        write: true,
+        execute: false,
+    };
+    pub const EXECUTE: Access = Access {
+        read: false,
+        write: false,
+        execute: true,
+    };
+
+    fn overlaps(self, other: Access) -> bool {
+        (self.read && other.read) || (self.write && other.write) || (self.execute && other.execute)
+    }
+}
+
+/// "r", "w", "x" or a combination such as "rw".
+impl FromStr for Access {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        let valid = !text.is_empty() && text.chars().all(|c| matches!(c, 'r' | 'w' | 'x'));
+        if !valid {
+            return Err(format!(
+                "invalid access '{}', expected r, w, x or a mix",
+                text
+            ));
+        }
+        Ok(Access {
+            read: text.contains('r'),
+            write: text.contains('w'),
+            execute: text.contains('x'),
+        })
+    }
+}
+
+impl fmt::Display for Access {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag = |set: bool, name: char| if set { name } else { '-' };
+        write!(
+            f,
+            "{}{}{}",
+            flag(self.read, 'r'),
+            flag(self.write, 'w'),
+            flag(self.execute, 'x')
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub access: Access,
+}
+
+impl Watchpoint {
+    pub fn new(range: RangeInclusive<u16>, access: Access) -> Self {
+        Watchpoint { range, access }
+    }
+
+    pub fn matches(&self, address: u16, access: Access) -> bool {
+        self.access.overlaps(access) && self.range.contains(&address)
+    }
+}
+
+/// Why `CPU::step` stopped. The CPU stops after the instruction, before the one at PC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Breakpoint(u16),
+    /// `pc` is the instruction that made the access. Execute watchpoints stop with PC on
+    /// `address`, like breakpoints.
+    Watchpoint {
+        address: u16,
+        access: Access,
+        value: u8,
+        pc: u16,
+    },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Stop::Breakpoint(address) => write!(f, "Breakpoint at ${:04X}", address),
+            Stop::Watchpoint {
+                address,
+                access,
+                pc,
+                ..
+            } if access.execute => {
+                write!(f, "Watchpoint: execute ${:04X} (from ${:04X})", address, pc)
+            }
+            Stop::Watchpoint {
+                address,
+                access,
+                value,
+                pc,
+            } => {
+                let verb = if access.write { "write" } else { "read" };
+                write!(
+                    f,
+                    "Watchpoint: {} ${:02X} at ${:04X} by ${:04X}",
+                    verb, value, address, pc
+                )
+            }
+        }
+    }
+}
+
+/// Hex number, with or without a $ or 0x prefix.
+pub fn parse_hex(text: &str) -> Result<u16, String> {
+    let digits = text
+        .strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("invalid number '{}'", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(Model::DMG);
+        cpu.registers.a = 0x10;
+        cpu.registers.b = 0x00;
+        cpu.registers.c = 0x08;
+        cpu.set_hl(0xC000);
+        cpu.set_zf(true);
+        cpu.ime = false;
+        cpu
+    }
+
+    fn eval(condition: &str) -> bool {
+        condition.parse::<Condition>().unwrap().eval(&cpu())
+    }
+
+    #[test]
+    fn parses_hex_with_or_without_a_prefix() {
+        assert_eq!(parse_hex("$1F"), Ok(0x1F));
+        assert_eq!(parse_hex("0xc000"), Ok(0xC000));
+        assert_eq!(parse_hex("10"), Ok(0x10));
+        assert_eq!(
+            parse_hex("10000"),
+            Err("invalid number '10000'".to_string())
+        );
+        assert_eq!(parse_hex(""), Err("invalid number ''".to_string()));
+    }
+
+    #[test]
+    fn compares_registers() {
+        assert!(eval("a == $10"));
+        assert!(eval("A==10"));
+        assert!(!eval("a != 10"));
+        assert!(eval("hl >= C000"));
+        assert!(!eval("hl > C000"));
+        assert!(eval("c <= 8"));
+        assert!(!eval("c < 8"));
+        assert!(eval("zf == 1 && ime == 0"));
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        assert!(eval("b != 0 || c < 9"));
+        assert!(eval("a == 0 && b == 0 || c == 8"));
+        assert!(!eval("a == 0 && c == 8 || b == 1"));
+        assert!(eval("a == 0 || b == 0 && c == 8"));
+    }
+
+    #[test]
+    fn shows_conditions_in_one_form() {
+        let condition: Condition = "HL>=0xc000&&zf==1 || b != 0".parse().unwrap();
+        assert_eq!(condition.to_string(), "hl >= $C000 && zf == $1 || b != $0");
+        assert_eq!(condition.to_string().parse(), Ok(condition));
+    }
+
+    #[test]
+    fn rejects_bad_conditions() {
+        let error = |text: &str| text.parse::<Condition>().unwrap_err();
+        assert_eq!(error("a = 1"), "'a = 1' is not a comparison");
+        assert_eq!(error("x == 1"), "unknown register 'x'");
+        assert_eq!(error("a == z"), "invalid number 'z'");
+        assert_eq!(error("a == 1 &&"), "'' is not a comparison");
+    }
+
+    #[test]
+    fn parses_access() {
+        assert_eq!("r".parse(), Ok(Access::READ));
+        assert_eq!("w".parse(), Ok(Access::WRITE));
+        assert_eq!("x".parse(), Ok(Access::EXECUTE));
+        let rw: Access = "wr".parse().unwrap();
+        assert_eq!(rw.to_string(), "rw-");
+        assert!(rw.overlaps(Access::WRITE) && !rw.overlaps(Access::EXECUTE));
+        assert_eq!(
+            "".parse::<Access>(),
+            Err("invalid access '', expected r, w, x or a mix".to_string())
+        );
+        assert!("rwa".parse::<Access>().is_err());
+    }
+
+    #[test]
+    fn watchpoints_match_range_and_access() {
+        let watchpoint = Watchpoint::new(0xC000..=0xC0FF, "rw".parse().unwrap());
+        assert!(watchpoint.matches(0xC000, Access::READ));
+        assert!(watchpoint.matches(0xC0FF, Access::WRITE));
+        assert!(!watchpoint.matches(0xC100, Access::WRITE));
+        assert!(!watchpoint.matches(0xC080, Access::EXECUTE));
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+const MAX_DEPTH: usize = 256; // Runaway code calls forever: the oldest frames go first
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+/// A subroutine or interrupt handler that hasn't returned yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// The CALL or RST instruction, or the instruction an interrupt came before.
+    pub call_site: u16,
+    pub bank: u16, // Of the call site, for symbols
+    pub target: u16,
+    pub target_bank: u16,
+    /// SP after the return address was pushed.
+    pub sp: u16,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            FrameKind::Call => write!(f, "CALL ${:04X}", self.target),
+            FrameKind::Rst => write!(f, "RST ${:02X}", self.target),
+            FrameKind::Interrupt => write!(f, "interrupt ${:02X}", self.target),
+        }
+    }
+}
+
+/*
+Shadow call stack: what the CPU called, kept aside from the real stack in memory. Code that
+juggles return addresses (POP them, JP HL, reset SP) doesn't fool it for long: a return drops
+every frame at or below the SP it pops from, not just the last one.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct CallStack {
+    frames: Vec<Frame>,
+}
+
+impl CallStack {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn call(&mut self, frame: Frame) {
+        // Frames the new one overwrote on the real stack are gone already
+        self.ret(frame.sp);
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.remove(0);
+        }
+        self.frames.push(frame);
+    }
+
+    /// A return popping its address from `sp`.
+    pub fn ret(&mut self, sp: u16) {
+        while self.frames.last().is_some_and(|frame| frame.sp <= sp) {
+            self.frames.pop();
+        }
+    }
+
+    /// Innermost last.
+    pub fn frames(&self) -> &[Frame] {
+        &self.frames
+    }
+
+    pub fn depth(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(target: u16, sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            call_site: 0x0150,
+            bank: 0,
+            target,
+            target_bank: 0,
+            sp,
+        }
+    }
+
+    fn targets(stack: &CallStack) -> Vec<u16> {
+        stack.frames().iter().map(|frame| frame.target).collect()
+    }
+
+    #[test]
+    fn returns_pop_the_frame_they_return_from() {
+        let mut stack = CallStack::new();
+        stack.call(call(0x1000, 0xFFFC));
+        stack.call(call(0x2000, 0xFFFA));
+        stack.ret(0xFFFA);
+        assert_eq!(targets(&stack), [0x1000]);
+        stack.ret(0xFFFC);
+        assert_eq!(stack.depth(), 0);
+        stack.ret(0xFFFE); // Nothing to return from
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn returns_past_frames_drop_them_all() {
+        let mut stack = CallStack::new();
+        stack.call(call(0x1000, 0xFFFC));
+        stack.call(call(0x2000, 0xFFFA));
+        stack.call(call(0x3000, 0xFFF8));
+        // The inner functions POPed their return addresses and jumped back
+        stack.ret(0xFFFC);
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn calls_over_dropped_frames_replace_them() {
+        let mut stack = CallStack::new();
+        stack.call(call(0x1000, 0xFFFC));
+        stack.call(call(0x2000, 0xFFFA));
+        // SP was reset: this return address overwrites the first one
+        stack.call(call(0x3000, 0xFFFC));
+        assert_eq!(targets(&stack), [0x3000]);
+    }
+
+    #[test]
+    fn forgets_the_oldest_frames_past_the_limit() {
+        let mut stack = CallStack::new();
+        for i in 0..MAX_DEPTH as u16 + 2 {
+            stack.call(call(i, 0xFFFE - i * 2));
+        }
+        assert_eq!(stack.depth(), MAX_DEPTH);
+        assert_eq!(stack.frames()[0].target, 2);
+        stack.clear();
+        assert_eq!(stack.depth(), 0);
+    }
+
+    #[test]
+    fn shows_frames_as_instructions() {
+        let mut frame = call(0x0150, 0xFFFC);
+        assert_eq!(frame.to_string(), "CALL $0150");
+        frame.kind = FrameKind::Rst;
+        frame.target = 0x38;
+        assert_eq!(frame.to_string(), "RST $38");
+        frame.kind = FrameKind::Interrupt;
+        frame.target = 0x40;
+        assert_eq!(frame.to_string(), "interrupt $40");
+    }
+}
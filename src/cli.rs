@@ -0,0 +1,277 @@
+use std::path::PathBuf;
+
+use Rustboy::apu::DEFAULT_SAMPLE_RATE;
+use Rustboy::model::Model;
+use Rustboy::tiles::Palette;
+
+pub const USAGE: &str = "\
+Usage: Rustboy [OPTIONS] <ROM>
+       Rustboy --record-audio <FILE> [OPTIONS] <GBS>
+
+GBS music files are played headlessly and exported to WAV, --frames long (default: 3600).
+
+Options:
+  --boot-rom <FILE>      Boot ROM image to run before the cartridge
+  --model <MODEL>        Hardware model: dmg0, dmg, mgb, sgb, sgb2, cgb, agb (default: dmg)
+  --headless             Run without frame pacing or display. Stops as soon as a test ROM
+                         reports a result
+  --frames <N>           Stop after N frames
+  --trace <FILE>         Write a CPU trace line per instruction to FILE
+  --symbols <FILE>       RGBDS symbol file with labels for the debugger and trace
+                         (default: the ROM's .sym, if there is one)
+  --profile <FILE>       Write the hottest addresses and the time spent per bank to FILE
+  --profile-folded <FILE>
+                         Write M-cycles per call stack to FILE, for flamegraph.pl or inferno
+  --cdl <FILE>           Log which ROM bytes run as code or are read as data, to FILE.
+                         An existing log for the ROM is added to. Writes to ROM aren't
+                         saved, CDL has no flag for them; the debugger's coverage counts them
+  --tiles <FILE>         Save the VRAM tiles as a PNG sheet when emulation ends (on CGB,
+                         bank 1 next to bank 0)
+  --tiles-frame <N>      Save the tile sheet at the end of frame N instead
+  --tiles-palette <NAME> Tile sheet colors: grey, green, or the bgp, obp0 or obp1 register
+                         (default: grey)
+  --debug                Start in the interactive debugger, paused before the first instruction
+  --gdb <PORT>           Wait for gdb (or another GDB remote protocol client) on localhost:PORT
+                         and let it drive emulation
+  --speed <FACTOR>       Emulation speed multiplier, 0 for unlimited (default: 1)
+  --save-dir <DIR>       Directory for battery saves (default: next to the ROM, or none in
+                         headless runs)
+  --record-movie <FILE>  Record the joypad input of every frame to FILE. With --play-movie,
+                         the input played back is recorded (past its end with --frames)
+  --play-movie <FILE>    Replay a recorded input movie (headless runs stop at its end)
+  --load-state <FILE>    Restore a save state before running
+  --save-state <FILE>    Write a save state when emulation ends
+  --audio-rate <HZ>      Output sample rate (default: 48000)
+  --audio-out <FILE>     Stream raw signed 16-bit stereo PCM to FILE, e.g. a pipe to a
+                         player. Emulation is then paced by the audio consumer
+  --record-audio <FILE>  Write the mixed audio output to a WAV file
+  --record-channels      With --record-audio, also write each channel to FILE-chN.wav
+  --song <N>             GBS files: export only song N (default: every song, to FILE-NN.wav,
+                         starting with the file's first song)
+  --link-listen <ADDR>   Wait for another emulator to plug into the serial port.
+                         ADDR is host:port, or unix:<path> for a Unix socket
+  --link-connect <ADDR>  Connect the serial port to an emulator started with --link-listen
+  --printer <DIR>        Plug a Game Boy Printer into the serial port, saving pages to DIR
+  -h, --help             Print this help
+
+Exit codes:
+  0  Emulation finished (or a test ROM passed)
+  1  A test ROM failed, as reported through the serial port or at 0xA000 in cartridge RAM
+  2  Invalid arguments or I/O error";
+
+pub struct Args {
+    pub rom: PathBuf,
+    pub boot_rom: Option<PathBuf>,
+    pub model: Model,
+    pub headless: bool,
+    pub frames: Option<u64>,
+    pub trace: Option<PathBuf>,
+    pub symbols: Option<PathBuf>,
+    pub profile: Option<PathBuf>,
+    pub profile_folded: Option<PathBuf>,
+    pub cdl: Option<PathBuf>,
+    pub tiles: Option<PathBuf>,
+    pub tiles_frame: Option<u64>,
+    pub tiles_palette: Palette,
+    pub debug: bool,
+    pub gdb: Option<u16>,
+    pub speed: f64,
+    pub save_dir: Option<PathBuf>,
+    pub record_movie: Option<PathBuf>,
+    pub play_movie: Option<PathBuf>,
+    pub load_state: Option<PathBuf>,
+    pub save_state: Option<PathBuf>,
+    pub audio_rate: u32,
+    pub audio_out: Option<PathBuf>,
+    pub record_audio: Option<PathBuf>,
+    pub record_channels: bool,
+    pub song: Option<u8>,
+    pub link: Option<LinkMode>,
+}
+
+pub enum LinkMode {
+    Listen(String),
+    Connect(String),
+    Printer(PathBuf),
+}
+
+pub enum Command {
+    Run(Box<Args>),
+    Help,
+}
+
+pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Command, String> {
+    let mut rom = None;
+    let mut boot_rom = None;
+    let mut model = Model::default();
+    let mut headless = false;
+    let mut frames = None;
+    let mut trace = None;
+    let mut symbols = None;
+    let mut profile = None;
+    let mut profile_folded = None;
+    let mut cdl = None;
+    let mut tiles = None;
+    let mut tiles_frame = None;
+    let mut tiles_palette = Palette::default();
+    let mut debug = false;
+    let mut gdb = None;
+    let mut speed = 1.0;
+    let mut save_dir = None;
+    let mut record_movie = None;
+    let mut play_movie = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut audio_rate = DEFAULT_SAMPLE_RATE;
+    let mut audio_out = None;
+    let mut record_audio = None;
+    let mut record_channels = false;
+    let mut song = None;
+    let mut link = None;
+
+    while let Some(arg) = args.next() {
+        // Accept both "--flag value" and "--flag=value"
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => {
+                (flag.to_string(), Some(value.to_string()))
+            }
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+
+        match flag.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--boot-rom" => boot_rom = Some(PathBuf::from(value("--boot-rom")?)),
+            "--model" => model = value("--model")?.parse()?,
+            "--headless" => headless = true,
+            "--frames" => {
+                let n = value("--frames")?;
+                frames = Some(
+                    n.parse()
+                        .map_err(|_| format!("invalid frame count '{}'", n))?,
+                );
+            }
+            "--trace" => trace = Some(PathBuf::from(value("--trace")?)),
+            "--symbols" => symbols = Some(PathBuf::from(value("--symbols")?)),
+            "--profile" => profile = Some(PathBuf::from(value("--profile")?)),
+            "--profile-folded" => profile_folded = Some(PathBuf::from(value("--profile-folded")?)),
+            "--cdl" => cdl = Some(PathBuf::from(value("--cdl")?)),
+            "--tiles" => tiles = Some(PathBuf::from(value("--tiles")?)),
+            "--tiles-frame" => {
+                let n = value("--tiles-frame")?;
+                tiles_frame = Some(
+                    n.parse()
+                        .map_err(|_| format!("invalid frame number '{}'", n))?,
+                );
+            }
+            "--tiles-palette" => tiles_palette = value("--tiles-palette")?.parse()?,
+            "--debug" => debug = true,
+            "--gdb" => {
+                let port = value("--gdb")?;
+                gdb = Some(
+                    port.parse()
+                        .map_err(|_| format!("invalid port '{}'", port))?,
+                );
+            }
+            "--speed" => {
+                let s = value("--speed")?;
+                speed = s
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|s| *s >= 0.0 && s.is_finite())
+                    .ok_or_else(|| format!("invalid speed '{}'", s))?;
+            }
+            "--save-dir" => save_dir = Some(PathBuf::from(value("--save-dir")?)),
+            "--record-movie" => record_movie = Some(PathBuf::from(value("--record-movie")?)),
+            "--play-movie" => play_movie = Some(PathBuf::from(value("--play-movie")?)),
+            "--load-state" => load_state = Some(PathBuf::from(value("--load-state")?)),
+            "--save-state" => save_state = Some(PathBuf::from(value("--save-state")?)),
+            "--audio-rate" => {
+                let rate = value("--audio-rate")?;
+                audio_rate = rate
+                    .parse()
+                    .ok()
+                    .filter(|rate| (8_000..=192_000).contains(rate))
+                    .ok_or_else(|| format!("invalid sample rate '{}'", rate))?;
+            }
+            "--audio-out" => audio_out = Some(PathBuf::from(value("--audio-out")?)),
+            "--record-audio" => record_audio = Some(PathBuf::from(value("--record-audio")?)),
+            "--record-channels" => record_channels = true,
+            "--song" => {
+                let n = value("--song")?;
+                song = Some(
+                    n.parse()
+                        .ok()
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| format!("invalid song number '{}'", n))?,
+                );
+            }
+            "--link-listen" | "--link-connect" | "--printer" => {
+                if link.is_some() {
+                    return Err(String::from(
+                        "only one of --link-listen, --link-connect and --printer can be given",
+                    ));
+                }
+                let value = value(&flag)?;
+                link = Some(match flag.as_str() {
+                    "--link-listen" => LinkMode::Listen(value),
+                    "--link-connect" => LinkMode::Connect(value),
+                    _ => LinkMode::Printer(PathBuf::from(value)),
+                });
+            }
+            _ if flag.starts_with('-') => return Err(format!("unknown option '{}'", flag)),
+            _ if rom.is_none() => rom = Some(PathBuf::from(arg)),
+            _ => return Err(format!("unexpected argument '{}'", arg)),
+        }
+    }
+
+    if debug && gdb.is_some() {
+        return Err(String::from("--debug and --gdb can't be used together"));
+    }
+    if tiles_frame.is_some() && tiles.is_none() {
+        return Err(String::from("--tiles-frame requires --tiles"));
+    }
+    if record_movie.is_some() && headless && play_movie.is_none() {
+        return Err(String::from(
+            "--record-movie in a headless run requires --play-movie, there is no other input",
+        ));
+    }
+    if record_channels && record_audio.is_none() {
+        return Err(String::from("--record-channels requires --record-audio"));
+    }
+
+    Ok(Command::Run(Box::new(Args {
+        rom: rom.ok_or("missing ROM path")?,
+        boot_rom,
+        model,
+        headless,
+        frames,
+        trace,
+        symbols,
+        profile,
+        profile_folded,
+        cdl,
+        tiles,
+        tiles_frame,
+        tiles_palette,
+        debug,
+        gdb,
+        speed,
+        save_dir,
+        record_movie,
+        play_movie,
+        load_state,
+        save_state,
+        audio_rate,
+        audio_out,
+        record_audio,
+        record_channels,
+        song,
+        link,
+    })))
+}
@@ -0,0 +1,137 @@
+use std::cell::Cell;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+Code/data log (CDL): one flag byte per ROM byte, in ROM file order, so bank N starts at
+N * 0x4000. No header. The flags are the ones FCEUX and Mesen logs use:
+    0x01  Code     Fetched by the CPU as part of an instruction
+    0x02  Data     Read by an instruction
+Writes (mapper registers, or a bug) are tracked too, but only in memory: bit 7 means something
+else in those logs, so it never goes to the file.
+*/
+
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const WRITTEN: u8 = 0x80;
+const SAVED: u8 = CODE | DATA;
+
+const BANK_LENGTH: usize = 0x4000;
+
+/// Bytes of one bank with each flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankCoverage {
+    pub bank: usize,
+    pub length: usize,
+    pub code: usize,
+    pub data: usize,
+    pub written: usize,
+    pub covered: usize, // Code or data
+}
+
+/// Which ROM bytes the CPU executed, read or wrote.
+#[derive(Debug, Clone, Default)]
+pub struct Coverage {
+    flags: Vec<Cell<u8>>, // Cells: reads mark memory through a shared MMU
+}
+
+impl Coverage {
+    pub fn new(rom_length: usize) -> Self {
+        Coverage {
+            flags: vec![Cell::new(0); rom_length],
+        }
+    }
+
+    /// Continues a log saved before for the same ROM.
+    pub fn load<P: AsRef<Path>>(path: P, rom_length: usize) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        if data.len() != rom_length {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "code/data log is {} bytes, the ROM is {}",
+                    data.len(),
+                    rom_length
+                ),
+            ));
+        }
+        Ok(Coverage {
+            flags: data
+                .into_iter()
+                .map(|flags| Cell::new(flags & SAVED))
+                .collect(),
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+
+    /// The log as saved, without the write flags.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.flags.iter().map(|flags| flags.get() & SAVED).collect()
+    }
+
+    /// Adds `flag` to the ROM byte at `offset`.
+    #[inline]
+    pub fn mark(&self, offset: usize, flag: u8) {
+        if let Some(flags) = self.flags.get(offset) {
+            flags.set(flags.get() | flag);
+        }
+    }
+
+    pub fn flags(&self, offset: usize) -> u8 {
+        self.flags.get(offset).map_or(0, Cell::get)
+    }
+
+    /// Counts per 16 KiB bank.
+    pub fn banks(&self) -> Vec<BankCoverage> {
+        self.flags
+            .chunks(BANK_LENGTH)
+            .enumerate()
+            .map(|(bank, flags)| {
+                let count = |flag: u8| flags.iter().filter(|f| f.get() & flag != 0).count();
+                BankCoverage {
+                    bank,
+                    length: flags.len(),
+                    code: count(CODE),
+                    data: count(DATA),
+                    written: count(WRITTEN),
+                    covered: count(CODE | DATA),
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_stay_out_of_the_saved_log() {
+        let coverage = Coverage::new(0x8000);
+        coverage.mark(0x0150, CODE);
+        coverage.mark(0x0150, WRITTEN);
+        coverage.mark(0x4000, DATA | WRITTEN);
+        coverage.mark(0x8000, CODE); // Past the end of the ROM
+
+        assert_eq!(coverage.flags(0x0150), CODE | WRITTEN);
+        let bytes = coverage.to_bytes();
+        assert_eq!(bytes.len(), 0x8000);
+        assert_eq!(bytes[0x0150], CODE);
+        assert_eq!(bytes[0x4000], DATA);
+
+        let banks = coverage.banks();
+        assert_eq!(banks.len(), 2);
+        assert_eq!(
+            (banks[0].code, banks[0].written, banks[0].covered),
+            (1, 1, 1)
+        );
+        assert_eq!(
+            (banks[1].data, banks[1].written, banks[1].covered),
+            (1, 1, 1)
+        );
+    }
+}
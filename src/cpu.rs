@@ -1,5 +1,12 @@
+use std::io;
+
+use crate::breakpoint::{Access, Breakpoint, Stop};
+use crate::callstack::{CallStack, Frame, FrameKind};
 use crate::mmu::MMU;
+use crate::model::Model;
 use crate::op_codes::execute_opcode;
+use crate::profiler::Profiler;
+use crate::savestate::{SaveState, StateReader, StateWriter};
 
 const DIV_INCREMENT_RATE: u32 = 256 / 4; // M-cycles
 
@@ -34,16 +41,26 @@ pub enum ControlRegisters {
 }
 
 impl Registers {
-    fn new() -> Registers {
+    // State left by the boot ROM of each model. Games read A (and B) to detect the hardware
+    fn new(model: Model) -> Registers {
+        let [a, f, b, c, d, e, h, l] = match model {
+            Model::DMG0 => [0x01, 0x00, 0xFF, 0x13, 0x00, 0xC1, 0x84, 0x03],
+            Model::DMG => [0x01, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::MGB => [0xFF, 0xB0, 0x00, 0x13, 0x00, 0xD8, 0x01, 0x4D],
+            Model::SGB => [0x01, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::SGB2 => [0xFF, 0x00, 0x00, 0x14, 0x00, 0x00, 0xC0, 0x60],
+            Model::CGB => [0x11, 0x80, 0x00, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+            Model::AGB => [0x11, 0x00, 0x01, 0x00, 0xFF, 0x56, 0x00, 0x0D],
+        };
         Registers {
-            a: 0x01,
-            f: 0xB0,
-            b: 0x00,
-            c: 0x13,
-            d: 0x00,
-            e: 0xD8,
-            h: 0x01,
-            l: 0x4D,
+            a,
+            f,
+            b,
+            c,
+            d,
+            e,
+            h,
+            l,
             pc: 0x0100, // Start address of the program
             sp: 0xFFFE, // Initial stack pointer
 
@@ -56,6 +73,22 @@ impl Registers {
                         CF:bool,    // Si hay acarreo fuera de rango */
         }
     }
+
+    // State at power on, before the boot ROM has run
+    fn power_on() -> Registers {
+        Registers {
+            a: 0,
+            f: 0,
+            b: 0,
+            c: 0,
+            d: 0,
+            e: 0,
+            h: 0,
+            l: 0,
+            pc: 0x0000,
+            sp: 0x0000,
+        }
+    }
 }
 
 pub struct CPU {
@@ -66,18 +99,40 @@ pub struct CPU {
     pub div_counter: u32,
     pub tima_counter: u32,
     pub ime: bool, // Interrupciones maestras habilitadas
+    pub breakpoints: Vec<Breakpoint>,
+    pub call_stack: CallStack,
+    pub profiler: Option<Box<Profiler>>,
+}
+
+/// What `CPU::step` did.
+pub struct Step {
+    pub cycles: u32, // M-cycles
+    /// Set when a breakpoint or watchpoint was hit. Without any, this is never checked.
+    pub stop: Option<Stop>,
 }
 
 impl CPU {
-    pub fn new() -> CPU {
+    pub fn new(model: Model) -> CPU {
         CPU {
-            registers: Registers::new(),
+            registers: Registers::new(model),
             ei_flag: false,
             stop_flag: false,
             halt_flag: false,
-            div_counter: 0,
+            div_counter: model.div_phase().1,
             tima_counter: 0,
             ime: false,
+            breakpoints: Vec::new(),
+            call_stack: CallStack::new(),
+            profiler: None,
+        }
+    }
+
+    /// CPU starting at 0x0000 so that a mapped boot ROM runs first.
+    pub fn new_boot_rom() -> CPU {
+        CPU {
+            registers: Registers::power_on(),
+            div_counter: 0,
+            ..CPU::default()
         }
     }
 
@@ -297,19 +352,14 @@ impl CPU {
 
     pub fn inc(&mut self, value: u8) -> u8 {
         let result = value.wrapping_add(1);
-        self.update_flags(
-            result as u8 == 0,
-            self.get_cf(),
-            (value & 0x0F) + 1 > 0x0F,
-            false,
-        );
+        self.update_flags(result == 0, self.get_cf(), (value & 0x0F) + 1 > 0x0F, false);
 
         result
     }
 
     pub fn dec(&mut self, value: u8) -> u8 {
         let result = value.wrapping_sub(1);
-        self.update_flags(result as u8 == 0, self.get_cf(), (value & 0x0F) < 1, true);
+        self.update_flags(result == 0, self.get_cf(), (value & 0x0F) < 1, true);
 
         result
     }
@@ -389,15 +439,15 @@ impl CPU {
     }
 
     pub fn fetch_byte(&mut self, mmu: &MMU) -> u8 {
-        let op = mmu.read_byte(self.registers.pc);
+        let op = mmu.read_code(self.registers.pc);
         self.registers.pc += 1;
         op
     }
 
     pub fn fetch_word(&mut self, mmu: &MMU) -> u16 {
-        let op = mmu.read_word(self.registers.pc);
-        self.registers.pc += 2;
-        op
+        let low = self.fetch_byte(mmu) as u16;
+        let high = self.fetch_byte(mmu) as u16;
+        (high << 8) | low
     }
 
     pub fn jr(&mut self, condition: bool, mmu: &MMU) {
@@ -487,12 +537,15 @@ impl CPU {
     }
 
     pub fn rst(&mut self, address: u16, mmu: &mut MMU) {
+        let call_site = self.registers.pc.wrapping_sub(1);
         self.push(self.registers.pc, mmu);
+        self.enter(FrameKind::Rst, call_site, address, mmu);
         self.registers.pc = address;
     }
 
     pub fn ret(&mut self, condition: bool, mmu: &MMU) {
         if condition {
+            self.call_stack.ret(self.registers.sp);
             self.registers.pc = self.pop(mmu);
         }
     }
@@ -501,17 +554,28 @@ impl CPU {
         let address = self.fetch_word(mmu);
 
         if condition {
+            let call_site = self.registers.pc.wrapping_sub(3);
             self.push(self.registers.pc, mmu);
+            self.enter(FrameKind::Call, call_site, address, mmu);
             self.registers.pc = address;
         }
     }
 
+    // Call stack bookkeeping, once the return address is on the stack
+    fn enter(&mut self, kind: FrameKind, call_site: u16, target: u16, mmu: &MMU) {
+        self.call_stack.call(Frame {
+            kind,
+            call_site,
+            bank: mmu.mapped_bank(call_site),
+            target,
+            target_bank: mmu.mapped_bank(target),
+            sp: self.registers.sp,
+        });
+    }
+
     pub fn increment_div_register(&mut self, mmu: &mut MMU) {
         if self.div_counter >= DIV_INCREMENT_RATE {
-            mmu.write_byte(
-                ControlRegisters::DIV as u16,
-                mmu.read_byte(ControlRegisters::DIV as u16).wrapping_add(1),
-            );
+            mmu.increment_div(); // Writing DIV would reset it
             self.div_counter -= DIV_INCREMENT_RATE; // Reset the cycle counter
         }
     }
@@ -538,28 +602,40 @@ impl CPU {
         }
     }
 
-    pub fn step(&mut self, mmu: &mut MMU) -> u32 {
+    pub fn step(&mut self, mmu: &mut MMU) -> Step {
+        let debugging = !self.breakpoints.is_empty() || !mmu.watchpoints.is_empty();
+        if debugging {
+            // Reads since the last step weren't the program's, e.g. a debugger showing memory
+            mmu.take_watch_hit();
+        }
+        let pc = self.registers.pc;
+        let halted = self.halt_flag;
+        mmu.start_instruction();
+        // Before the instruction runs, as it may switch banks
+        let bank = self.profiler.as_mut().map(|profiler| {
+            profiler.enter(&self.call_stack);
+            mmu.mapped_bank(pc)
+        });
+        let mut watch_hit = None;
         let mut cycles = 0;
 
         // Handle HALT
         if self.halt_flag {
-            loop {
-                // Still running div and tima registers
-                self.increment_div_register(mmu);
-                self.increment_tima_register(mmu);
-
-                // Exit on interrupt
-                if (mmu.read_byte(ControlRegisters::IF as u16)
-                    & mmu.read_byte(ControlRegisters::IE as u16))
-                    != 0
-                {
-                    self.halt_flag = false;
-                    self.handle_interrupts(mmu);
-                    break;
-                }
+            // One M-cycle at a time, so the timer, serial port and APU keep running meanwhile.
+            // Exit on interrupt, dispatched below if IME is set
+            cycles += 1;
+            if (mmu.read_byte(ControlRegisters::IF as u16)
+                & mmu.read_byte(ControlRegisters::IE as u16))
+                != 0
+            {
+                self.halt_flag = false;
             }
         } else {
             cycles += execute_opcode(self, mmu) as u32;
+            // Timer and interrupt accesses below are the hardware's, not the program's
+            if debugging {
+                watch_hit = mmu.take_watch_hit();
+            }
         }
 
         self.tima_counter = self.tima_counter.wrapping_add(cycles);
@@ -583,10 +659,65 @@ impl CPU {
         // Increment the TIMA register
         self.increment_tima_register(mmu);
 
-        cycles + cycles2
+        if let (Some(profiler), Some(bank)) = (self.profiler.as_mut(), bank) {
+            profiler.record(bank, pc, cycles + cycles2, !halted);
+        }
+
+        let stop = if debugging {
+            self.check_stop(pc, watch_hit, mmu)
+        } else {
+            None
+        };
+        Step {
+            cycles: cycles + cycles2,
+            stop,
+        }
+    }
+
+    // `pc` is the instruction that just ran. Breakpoints are checked on the next one
+    fn check_stop(&self, pc: u16, watch_hit: Option<(u16, Access, u8)>, mmu: &MMU) -> Option<Stop> {
+        if let Some((address, access, value)) = watch_hit {
+            return Some(Stop::Watchpoint {
+                address,
+                access,
+                value,
+                pc,
+            });
+        }
+        // Still halted: the next instruction isn't about to run yet
+        if self.halt_flag {
+            return None;
+        }
+        let next = self.registers.pc;
+        if self
+            .breakpoints
+            .iter()
+            .any(|b| b.address == next && b.hit(self))
+        {
+            return Some(Stop::Breakpoint(next));
+        }
+        if mmu.watches(next, Access::EXECUTE) {
+            return Some(Stop::Watchpoint {
+                address: next,
+                access: Access::EXECUTE,
+                value: mmu.read_code(next),
+                pc,
+            });
+        }
+        None
     }
 
     fn handle_interrupts(&mut self, mmu: &mut MMU) -> u32 {
+        let pc = self.registers.pc;
+        let cycles = self.dispatch_interrupt(mmu);
+        if cycles > 0 {
+            let vector = self.registers.pc;
+            self.enter(FrameKind::Interrupt, pc, vector, mmu);
+        }
+        cycles
+    }
+
+    fn dispatch_interrupt(&mut self, mmu: &mut MMU) -> u32 {
         if self.ei_flag {
             self.ei_flag = false;
             self.ime = true;
@@ -636,6 +767,72 @@ impl CPU {
                 return 5;
             }
         }
-        return 0;
+        0
+    }
+}
+
+impl Default for CPU {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
+}
+
+impl SaveState for CPU {
+    fn save_state(&self, w: &mut StateWriter) {
+        let r = &self.registers;
+        w.bytes(&[r.a, r.f, r.b, r.c, r.d, r.e, r.h, r.l]);
+        w.u16(r.pc);
+        w.u16(r.sp);
+        w.bool(self.ei_flag);
+        w.bool(self.stop_flag);
+        w.bool(self.halt_flag);
+        w.bool(self.ime);
+        w.u32(self.div_counter);
+        w.u32(self.tima_counter);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        let regs = &mut self.registers;
+        let [a, f, b, c, d, e, h, l]: [u8; 8] = r.bytes(8)?.try_into().unwrap();
+        (regs.a, regs.f, regs.b, regs.c) = (a, f, b, c);
+        (regs.d, regs.e, regs.h, regs.l) = (d, e, h, l);
+        regs.pc = r.u16()?;
+        regs.sp = r.u16()?;
+        self.ei_flag = r.bool()?;
+        self.stop_flag = r.bool()?;
+        self.halt_flag = r.bool()?;
+        self.ime = r.bool()?;
+        self.div_counter = r.u32()?;
+        self.tima_counter = r.u32()?;
+        // Not part of the state: calls made before this point are unknown
+        self.call_stack.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registers_start_as_the_boot_rom_leaves_them() {
+        // Pan Docs, Power Up Sequence: AF, BC, DE, HL. DMG and MGB set H and C in F as the
+        // header checksum isn't 0
+        let table = [
+            (Model::DMG0, [0x0100, 0xFF13, 0x00C1, 0x8403]),
+            (Model::DMG, [0x01B0, 0x0013, 0x00D8, 0x014D]),
+            (Model::MGB, [0xFFB0, 0x0013, 0x00D8, 0x014D]),
+            (Model::SGB, [0x0100, 0x0014, 0x0000, 0xC060]),
+            (Model::SGB2, [0xFF00, 0x0014, 0x0000, 0xC060]),
+            (Model::CGB, [0x1180, 0x0000, 0xFF56, 0x000D]),
+            (Model::AGB, [0x1100, 0x0100, 0xFF56, 0x000D]),
+        ];
+        for (model, pairs) in table {
+            let cpu = CPU::new(model);
+            let registers = [cpu.get_af(), cpu.get_bc(), cpu.get_de(), cpu.get_hl()];
+            assert_eq!(registers, pairs, "{}", model);
+            assert_eq!((cpu.registers.pc, cpu.registers.sp), (0x0100, 0xFFFE));
+        }
+        assert_eq!(table.map(|(model, _)| model), Model::ALL);
     }
 }
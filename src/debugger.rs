@@ -0,0 +1,841 @@
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::breakpoint::{self, Access, Breakpoint, Condition, Stop, Watchpoint};
+use crate::coverage::Coverage;
+use crate::cpu::ControlRegisters;
+use crate::disasm::{self, Instruction};
+use crate::gameboy::GameBoy;
+use crate::gpu::GPUControlRegisters;
+use crate::tiles::{self, Palette};
+
+pub const HELP: &str = "\
+Commands (an empty line repeats the last one):
+  break [ADDR [if COND]]
+                      Set a breakpoint at ADDR, or list them (b). COND compares registers:
+                      a == $10 && hl >= C000 || zf == 1
+  delete ADDR|all     Remove a breakpoint
+  watch [ADDR[-END] [r|w|x]]
+                      Stop on reads, writes (default) or execution in a range, or list
+                      watchpoints. Accesses can be combined, e.g. rw
+  unwatch ADDR|all    Remove the watchpoints starting at ADDR
+  continue            Run until a breakpoint or watchpoint (c)
+  step [N]            Execute N instructions (s)
+  next [N]            Like step, but run CALL and RST through to their return (n)
+  finish              Run until the current subroutine returns
+  frame [N]           Run until the end of the Nth frame from now
+  back                Rewind to the last snapshot, up to a second back
+  regs                Registers and flags (r)
+  backtrace           Calls, RSTs and interrupts that haven't returned yet (bt)
+  x ADDR [LEN]        Hexdump LEN bytes of memory (default: 64)
+  write ADDR BYTE...  Write memory, without the side effects of a CPU write. ROM is patched
+                      in place (w)
+  disas [ADDR] [N]    Disassemble N instructions at ADDR, or around PC (d)
+  profile [start|stop|N]
+                      Start or stop the profiler, or show the N hottest addresses so far
+                      (default: 20)
+  coverage [start|stop|save FILE]
+                      Start or stop logging which ROM bytes are code and data, save the
+                      log as a CDL file, or show how much of each bank is covered
+  tiles FILE [PALETTE]
+                      Save the VRAM tiles as a PNG sheet, both banks on CGB. PALETTE is
+                      grey (default), green, bgp, obp0 or obp1
+  ppu                 PPU mode and LCD registers
+  timer               DIV/TIMA registers and counters
+  quit                Leave the debugger (q)
+Addresses are hex ($C000, 0xC000 or C000), a register pair (pc, sp, hl, bc, de, af) or a
+label from the symbol file. Labels in switchable banks stop in any bank at that address.
+x and write also take BANK:ADDR, to reach banks that aren't mapped: 02:4000, or 01:8000 for
+the second VRAM bank on CGB.
+Counts are decimal. ^C stops a running command and comes back to the prompt.";
+
+const DUMP_LENGTH: u16 = 64;
+const LISTING_BEFORE: usize = 4; // Instructions shown before PC
+const LISTING_LENGTH: usize = 10;
+const PROFILE_HOT_SPOTS: usize = 20;
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+extern "C" fn on_interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::Relaxed);
+}
+
+// While alive, ^C sets INTERRUPTED instead of killing the process. At the prompt it quits again.
+// Unix only: elsewhere ^C still ends the process
+struct CatchInterrupt;
+
+impl CatchInterrupt {
+    fn new() -> Self {
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        #[cfg(unix)]
+        // SAFETY: the handler only stores to an atomic
+        unsafe {
+            libc::signal(
+                libc::SIGINT,
+                on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+        CatchInterrupt
+    }
+}
+
+impl Drop for CatchInterrupt {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        // SAFETY: back to the default action
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+}
+
+/// How `run_until` ended.
+enum Outcome {
+    Done,
+    Stopped(Stop),
+    Interrupted,
+}
+
+/// Interactive debugger. Reads commands until `quit` or the end of the input. Breakpoints and
+/// watchpoints live in the CPU and MMU, so they stay set after the debugger returns.
+#[derive(Default)]
+pub struct Debugger {
+    last_command: String,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run<R: BufRead, W: Write>(
+        &mut self,
+        gameboy: &mut GameBoy,
+        input: R,
+        out: &mut W,
+    ) -> io::Result<()> {
+        writeln!(out, "Type help for the list of commands")?;
+        show_location(gameboy, out)?;
+        let mut lines = input.lines();
+        loop {
+            write!(out, "(rustboy) ")?;
+            out.flush()?;
+            let Some(line) = lines.next() else {
+                break;
+            };
+            let line = line?;
+            if !line.trim().is_empty() {
+                self.last_command = line;
+            }
+            let command = self.last_command.clone();
+            match self.execute(gameboy, &command, out) {
+                Ok(true) => break,
+                Ok(false) => {}
+                Err(e) => writeln!(out, "{}", e)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs one command line. Returns true on `quit`.
+    pub fn execute<W: Write>(
+        &mut self,
+        gameboy: &mut GameBoy,
+        line: &str,
+        out: &mut W,
+    ) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            return Ok(false);
+        };
+        let args: Vec<&str> = words.collect();
+        let io_error = |e: io::Error| e.to_string();
+
+        match command {
+            "help" | "h" => writeln!(out, "{}", HELP).map_err(io_error)?,
+            "quit" | "q" => return Ok(true),
+            "break" | "b" => match args.first() {
+                Some(arg) => {
+                    let address = parse_address(arg, gameboy)?;
+                    let condition = match args.get(1) {
+                        Some(&"if") => Some(args[2..].join(" ").parse::<Condition>()?),
+                        Some(arg) => return Err(format!("expected 'if', got '{}'", arg)),
+                        None => None,
+                    };
+                    let breakpoints = &mut gameboy.cpu.breakpoints;
+                    // One breakpoint per address: setting it again replaces the condition
+                    breakpoints.retain(|b| b.address != address);
+                    breakpoints.push(Breakpoint::new(address, condition));
+                    match gameboy.describe(address) {
+                        Some(label) => {
+                            writeln!(out, "Breakpoint set at ${:04X} <{}>", address, label)
+                        }
+                        None => writeln!(out, "Breakpoint set at ${:04X}", address),
+                    }
+                    .map_err(io_error)?;
+                }
+                None if gameboy.cpu.breakpoints.is_empty() => {
+                    writeln!(out, "No breakpoints").map_err(io_error)?
+                }
+                None => {
+                    for b in &gameboy.cpu.breakpoints {
+                        write!(out, "${:04X}", b.address).map_err(io_error)?;
+                        if let Some(label) = gameboy.describe(b.address) {
+                            write!(out, " <{}>", label).map_err(io_error)?;
+                        }
+                        match &b.condition {
+                            Some(condition) => writeln!(out, " if {}", condition),
+                            None => writeln!(out),
+                        }
+                        .map_err(io_error)?;
+                    }
+                }
+            },
+            "delete" => match args.first() {
+                Some(&"all") => gameboy.cpu.breakpoints.clear(),
+                Some(arg) => {
+                    let address = parse_address(arg, gameboy)?;
+                    let breakpoints = &mut gameboy.cpu.breakpoints;
+                    let count = breakpoints.len();
+                    breakpoints.retain(|b| b.address != address);
+                    if breakpoints.len() == count {
+                        return Err(format!("no breakpoint at ${:04X}", address));
+                    }
+                }
+                None => return Err(String::from("delete needs an address, or all")),
+            },
+            "watch" => match args.first() {
+                Some(arg) => {
+                    let range = match arg.split_once('-') {
+                        Some((start, end)) => {
+                            parse_address(start, gameboy)?..=parse_address(end, gameboy)?
+                        }
+                        None => {
+                            let address = parse_address(arg, gameboy)?;
+                            address..=address
+                        }
+                    };
+                    if range.is_empty() {
+                        return Err(format!("empty range '{}'", arg));
+                    }
+                    let access = match args.get(1) {
+                        Some(arg) => arg.parse()?,
+                        None => Access::WRITE,
+                    };
+                    let watchpoint = Watchpoint::new(range, access);
+                    show_watchpoint(&watchpoint, out).map_err(io_error)?;
+                    gameboy.mmu.watchpoints.push(watchpoint);
+                }
+                None if gameboy.mmu.watchpoints.is_empty() => {
+                    writeln!(out, "No watchpoints").map_err(io_error)?
+                }
+                None => {
+                    for watchpoint in &gameboy.mmu.watchpoints {
+                        show_watchpoint(watchpoint, out).map_err(io_error)?;
+                    }
+                }
+            },
+            "unwatch" => match args.first() {
+                Some(&"all") => gameboy.mmu.watchpoints.clear(),
+                Some(arg) => {
+                    let address = parse_address(arg, gameboy)?;
+                    let watchpoints = &mut gameboy.mmu.watchpoints;
+                    let count = watchpoints.len();
+                    watchpoints.retain(|w| *w.range.start() != address);
+                    if watchpoints.len() == count {
+                        return Err(format!("no watchpoint at ${:04X}", address));
+                    }
+                }
+                None => return Err(String::from("unwatch needs an address, or all")),
+            },
+            "continue" | "c" => {
+                let stop = run_until(gameboy, |_, _| false)?;
+                report(stop, gameboy, out).map_err(io_error)?;
+            }
+            "step" | "s" => {
+                let count = parse_count(args.first())?;
+                let mut steps = 0;
+                let stop = run_until(gameboy, |_, _| {
+                    steps += 1;
+                    steps == count
+                })?;
+                report(stop, gameboy, out).map_err(io_error)?;
+            }
+            "next" | "n" => {
+                let count = parse_count(args.first())?;
+                let mut stop = Outcome::Done;
+                for _ in 0..count {
+                    stop = next(gameboy)?;
+                    if !matches!(stop, Outcome::Done) {
+                        break;
+                    }
+                }
+                report(stop, gameboy, out).map_err(io_error)?;
+            }
+            "finish" => {
+                // The return address sits just above SP, so RET leaves SP higher than now
+                let sp = gameboy.cpu.registers.sp;
+                let stop = run_until(gameboy, |gameboy, opcode| {
+                    disasm::is_return(opcode) && gameboy.cpu.registers.sp > sp
+                })?;
+                report(stop, gameboy, out).map_err(io_error)?;
+            }
+            "frame" => {
+                let frame = gameboy.frame_count + parse_count(args.first())?;
+                let stop = run_until(gameboy, |gameboy, _| gameboy.frame_count >= frame)?;
+                report(stop, gameboy, out).map_err(io_error)?;
+            }
+            "back" => {
+                if gameboy.rewind().is_none() {
+                    return Err(String::from("rewind is off"));
+                }
+                if !gameboy.step_back().map_err(io_error)? {
+                    return Err(String::from("nothing to rewind to"));
+                }
+                writeln!(out, "Frame {}", gameboy.frame_count).map_err(io_error)?;
+                show_location(gameboy, out).map_err(io_error)?;
+            }
+            "regs" | "r" => show_registers(gameboy, out).map_err(io_error)?,
+            "backtrace" | "bt" => gameboy.write_stack_trace(out).map_err(io_error)?,
+            "x" => {
+                let (bank, address) =
+                    parse_location(args.first().ok_or("x needs an address")?, gameboy)?;
+                let length = match args.get(1) {
+                    Some(_) => parse_count(args.get(1))?.min(u16::MAX as u64) as u16,
+                    None => DUMP_LENGTH,
+                };
+                hexdump(gameboy, bank, address, length, out).map_err(io_error)?;
+            }
+            "write" | "w" => {
+                let (bank, address) =
+                    parse_location(args.first().ok_or("write needs an address")?, gameboy)?;
+                if args.len() < 2 {
+                    return Err(String::from("write needs at least one byte"));
+                }
+                let bytes = args[1..]
+                    .iter()
+                    .map(|arg| parse_byte(arg))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    let address = address.wrapping_add(i as u16);
+                    if !gameboy.mmu.poke_banked(bank, address, byte) {
+                        return Err(format!("no bank {:02X} at ${:04X}", bank, address));
+                    }
+                }
+            }
+            "disas" | "d" => {
+                let count = match args.get(1) {
+                    Some(_) => parse_count(args.get(1))? as usize,
+                    None => LISTING_LENGTH,
+                };
+                let addresses = match args.first() {
+                    Some(arg) => {
+                        let address = parse_address(arg, gameboy)?;
+                        listing(gameboy, address, count)
+                    }
+                    None => {
+                        let read = |address| gameboy.mmu.peek(address);
+                        let pc = gameboy.cpu.registers.pc;
+                        let mut addresses = disasm::preceding(read, pc, LISTING_BEFORE + 1);
+                        addresses.pop();
+                        let after = count.saturating_sub(addresses.len());
+                        addresses.extend(listing(gameboy, pc, after));
+                        addresses
+                    }
+                };
+                for address in addresses {
+                    show_instruction(gameboy, address, out).map_err(io_error)?;
+                }
+            }
+            "profile" => match args.first() {
+                Some(&"start") => gameboy.cpu.profiler = Some(Box::default()),
+                Some(&"stop") => gameboy.cpu.profiler = None,
+                arg => {
+                    let limit = match arg {
+                        Some(_) => parse_count(arg)? as usize,
+                        None => PROFILE_HOT_SPOTS,
+                    };
+                    let profiler = gameboy
+                        .cpu
+                        .profiler
+                        .as_ref()
+                        .ok_or("the profiler is off, start it with profile start")?;
+                    profiler
+                        .write_report(&gameboy.symbols, limit, out)
+                        .map_err(io_error)?;
+                }
+            },
+            "coverage" => match args.first() {
+                Some(&"start") => gameboy.mmu.coverage = Some(Coverage::new(gameboy.mmu.rom.len())),
+                Some(&"stop") => gameboy.mmu.coverage = None,
+                Some(&"save") => {
+                    let path = args.get(1).ok_or("coverage save needs a file")?;
+                    coverage(gameboy)?
+                        .save(path)
+                        .map_err(|e| format!("{}: {}", path, e))?;
+                }
+                Some(arg) => return Err(format!("unknown coverage command '{}'", arg)),
+                None => show_coverage(coverage(gameboy)?, out).map_err(io_error)?,
+            },
+            "tiles" => {
+                let path = args.first().ok_or("tiles needs a file")?;
+                let palette = match args.get(1) {
+                    Some(arg) => arg.parse()?,
+                    None => Palette::default(),
+                };
+                tiles::save(&gameboy.mmu, palette, path).map_err(|e| format!("{}: {}", path, e))?;
+                writeln!(out, "Tiles saved to {} ({})", path, palette).map_err(io_error)?;
+            }
+            "ppu" => show_ppu(gameboy, out).map_err(io_error)?,
+            "timer" => show_timer(gameboy, out).map_err(io_error)?,
+            _ => return Err(format!("unknown command '{}', try help", command)),
+        }
+        Ok(false)
+    }
+}
+
+// Steps over CALL and RST. Anything else is a single step
+fn next(gameboy: &mut GameBoy) -> Result<Outcome, String> {
+    let instruction = disassemble(gameboy, gameboy.cpu.registers.pc);
+    if !instruction.is_call() || gameboy.cpu.halt_flag {
+        return run_until(gameboy, |_, _| true);
+    }
+    let (ret, sp) = (instruction.next(), gameboy.cpu.registers.sp);
+    // A recursive call comes back to the same address with a deeper stack
+    run_until(gameboy, |gameboy, _| {
+        gameboy.cpu.registers.pc == ret && gameboy.cpu.registers.sp >= sp
+    })
+}
+
+/// Steps until `done` returns true, a breakpoint or watchpoint stops the CPU or the user presses
+/// ^C. `done` gets the opcode that was just executed. The first instruction always runs, even on
+/// a breakpoint.
+fn run_until<F: FnMut(&GameBoy, u8) -> bool>(
+    gameboy: &mut GameBoy,
+    mut done: F,
+) -> Result<Outcome, String> {
+    let _interrupt = CatchInterrupt::new();
+    loop {
+        let opcode = gameboy.mmu.peek(gameboy.cpu.registers.pc);
+        let frame = gameboy.frame_count;
+        gameboy.step().map_err(|e| e.to_string())?;
+        if gameboy.frame_count != frame {
+            // Nobody is listening while debugging
+            gameboy.mmu.apu.take_samples();
+        }
+
+        if let Some(stop) = gameboy.take_stop() {
+            return Ok(Outcome::Stopped(stop));
+        }
+        if done(gameboy, opcode) {
+            return Ok(Outcome::Done);
+        }
+        if INTERRUPTED.load(Ordering::Relaxed) {
+            return Ok(Outcome::Interrupted);
+        }
+    }
+}
+
+fn report<W: Write>(outcome: Outcome, gameboy: &GameBoy, out: &mut W) -> io::Result<()> {
+    match outcome {
+        Outcome::Done => {}
+        Outcome::Stopped(stop) => writeln!(out, "{}", stop)?,
+        Outcome::Interrupted => writeln!(out, "Interrupted")?,
+    }
+    show_location(gameboy, out)
+}
+
+fn show_instruction<W: Write>(gameboy: &GameBoy, address: u16, out: &mut W) -> io::Result<()> {
+    show_label(gameboy, address, out)?;
+    let marker = if address == gameboy.cpu.registers.pc {
+        "=>"
+    } else if gameboy.cpu.breakpoints.iter().any(|b| b.address == address) {
+        "*"
+    } else {
+        ""
+    };
+    write!(out, "{:<3}", marker)?;
+    write_instruction(gameboy, &disassemble(gameboy, address), out)
+}
+
+// Label line above the instruction it names, as in the source
+fn show_label<W: Write>(gameboy: &GameBoy, address: u16, out: &mut W) -> io::Result<()> {
+    match gameboy.label(address) {
+        Some(label) => writeln!(out, "{}:", label),
+        None => Ok(()),
+    }
+}
+
+fn show_watchpoint<W: Write>(watchpoint: &Watchpoint, out: &mut W) -> io::Result<()> {
+    let (start, end) = (watchpoint.range.start(), watchpoint.range.end());
+    if start == end {
+        writeln!(out, "Watchpoint {} ${:04X}", watchpoint.access, start)
+    } else {
+        writeln!(
+            out,
+            "Watchpoint {} ${:04X}-${:04X}",
+            watchpoint.access, start, end
+        )
+    }
+}
+
+fn disassemble(gameboy: &GameBoy, address: u16) -> Instruction {
+    disasm::disassemble(|address| gameboy.mmu.peek(address), address)
+}
+
+// Addresses of `count` instructions from `address` on
+fn listing(gameboy: &GameBoy, address: u16, count: usize) -> Vec<u16> {
+    let mut addresses = Vec::with_capacity(count);
+    let mut address = address;
+    for _ in 0..count {
+        addresses.push(address);
+        address = disassemble(gameboy, address).next();
+    }
+    addresses
+}
+
+fn write_instruction<W: Write>(
+    gameboy: &GameBoy,
+    instruction: &Instruction,
+    out: &mut W,
+) -> io::Result<()> {
+    let bytes: Vec<String> = instruction
+        .bytes
+        .iter()
+        .map(|b| format!("{:02X}", b))
+        .collect();
+    writeln!(
+        out,
+        "{:04X}: {:<9} {}",
+        instruction.address,
+        bytes.join(" "),
+        instruction.labeled_text(|address| gameboy.describe(address))
+    )
+}
+
+fn show_location<W: Write>(gameboy: &GameBoy, out: &mut W) -> io::Result<()> {
+    let pc = gameboy.cpu.registers.pc;
+    show_label(gameboy, pc, out)?;
+    if gameboy.cpu.halt_flag {
+        write!(out, "(halted) ")?;
+    }
+    write_instruction(gameboy, &disassemble(gameboy, pc), out)
+}
+
+fn show_registers<W: Write>(gameboy: &GameBoy, out: &mut W) -> io::Result<()> {
+    let cpu = &gameboy.cpu;
+    let r = &cpu.registers;
+    writeln!(
+        out,
+        "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X}",
+        cpu.get_af(),
+        cpu.get_bc(),
+        cpu.get_de(),
+        cpu.get_hl(),
+        r.sp,
+        r.pc
+    )?;
+    let flag = |set: bool, name: char| if set { name } else { '-' };
+    writeln!(
+        out,
+        "Flags {}{}{}{}  IME={} IE=${:02X} IF=${:02X}  HALT={} STOP={}",
+        flag(cpu.get_zf(), 'Z'),
+        flag(cpu.get_nf(), 'N'),
+        flag(cpu.get_hf(), 'H'),
+        flag(cpu.get_cf(), 'C'),
+        cpu.ime as u8,
+        gameboy.mmu.peek(ControlRegisters::IE as u16),
+        gameboy.mmu.peek(ControlRegisters::IF as u16),
+        cpu.halt_flag as u8,
+        cpu.stop_flag as u8
+    )
+}
+
+fn coverage(gameboy: &GameBoy) -> Result<&Coverage, String> {
+    gameboy
+        .mmu
+        .coverage
+        .as_ref()
+        .ok_or_else(|| String::from("coverage is off, start it with coverage start"))
+}
+
+fn show_coverage<W: Write>(coverage: &Coverage, out: &mut W) -> io::Result<()> {
+    writeln!(out, "Bank  Code   Data   Written  Covered")?;
+    for bank in coverage.banks() {
+        writeln!(
+            out,
+            "{:02X}    {:<6} {:<6} {:<8} {:5.1}%",
+            bank.bank,
+            bank.code,
+            bank.data,
+            bank.written,
+            100.0 * bank.covered as f64 / bank.length as f64
+        )?;
+    }
+    Ok(())
+}
+
+fn show_ppu<W: Write>(gameboy: &GameBoy, out: &mut W) -> io::Result<()> {
+    let read = |register: GPUControlRegisters| gameboy.mmu.peek(register as u16);
+    writeln!(
+        out,
+        "Mode {}  LY={} LYC={}  LCDC=${:02X} STAT=${:02X}  SCX={} SCY={} WX={} WY={}",
+        gameboy.gpu.ppu_mode,
+        read(GPUControlRegisters::LY),
+        read(GPUControlRegisters::LYC),
+        read(GPUControlRegisters::LCDC),
+        read(GPUControlRegisters::STAT),
+        read(GPUControlRegisters::SCX),
+        read(GPUControlRegisters::SCY),
+        read(GPUControlRegisters::WX),
+        read(GPUControlRegisters::WY)
+    )?;
+    writeln!(
+        out,
+        "Frame {}, M-cycle {}",
+        gameboy.frame_count,
+        gameboy.frame_cycles()
+    )
+}
+
+fn show_timer<W: Write>(gameboy: &GameBoy, out: &mut W) -> io::Result<()> {
+    let read = |register: ControlRegisters| gameboy.mmu.peek(register as u16);
+    writeln!(
+        out,
+        "DIV=${:02X} TIMA=${:02X} TMA=${:02X} TAC=${:02X}  div_counter={} tima_counter={}",
+        read(ControlRegisters::DIV),
+        read(ControlRegisters::TIMA),
+        read(ControlRegisters::TMA),
+        read(ControlRegisters::TAC),
+        gameboy.cpu.div_counter,
+        gameboy.cpu.tima_counter
+    )
+}
+
+// Bytes outside the bank show as --
+fn hexdump<W: Write>(
+    gameboy: &GameBoy,
+    bank: u16,
+    address: u16,
+    length: u16,
+    out: &mut W,
+) -> io::Result<()> {
+    let bytes: Vec<Option<u8>> = (0..length)
+        .map(|i| gameboy.mmu.peek_banked(bank, address.wrapping_add(i)))
+        .collect();
+    for (row, chunk) in bytes.chunks(16).enumerate() {
+        let row_address = address.wrapping_add(row as u16 * 16);
+        if gameboy.mmu.mapped_bank(row_address) != bank {
+            write!(out, "{:02X}:", bank)?;
+        }
+        write!(out, "{:04X} ", row_address)?;
+        for i in 0..16 {
+            match chunk.get(i) {
+                Some(Some(byte)) => write!(out, " {:02X}", byte)?,
+                Some(None) => write!(out, " --")?,
+                None => write!(out, "   ")?,
+            }
+        }
+        let text: String = chunk
+            .iter()
+            .map(|b| match b {
+                Some(b) if b.is_ascii_graphic() => *b as char,
+                _ => '.',
+            })
+            .collect();
+        writeln!(out, "  |{}|", text)?;
+    }
+    Ok(())
+}
+
+fn parse_byte(text: &str) -> Result<u8, String> {
+    let value = breakpoint::parse_hex(text).map_err(|_| format!("invalid byte '{}'", text))?;
+    u8::try_from(value).map_err(|_| format!("invalid byte '{}'", text))
+}
+
+fn parse_address(text: &str, gameboy: &GameBoy) -> Result<u16, String> {
+    let cpu = &gameboy.cpu;
+    match text.to_ascii_lowercase().as_str() {
+        "pc" => Ok(cpu.registers.pc),
+        "sp" => Ok(cpu.registers.sp),
+        "hl" => Ok(cpu.get_hl()),
+        "bc" => Ok(cpu.get_bc()),
+        "de" => Ok(cpu.get_de()),
+        "af" => Ok(cpu.get_af()),
+        // Labels first: some of them, like Add or Dead, are valid hex too
+        _ => match gameboy.symbols.lookup(text) {
+            Some((_, address)) => Ok(address),
+            None => breakpoint::parse_hex(text)
+                .map_err(|_| format!("invalid address or unknown label '{}'", text)),
+        },
+    }
+}
+
+// ADDR, or BANK:ADDR for memory that isn't mapped. Both hex
+fn parse_location(text: &str, gameboy: &GameBoy) -> Result<(u16, u16), String> {
+    match text.split_once(':') {
+        Some((bank, address)) => {
+            let bank =
+                breakpoint::parse_hex(bank).map_err(|_| format!("invalid bank '{}'", bank))?;
+            let address = parse_address(address, gameboy)?;
+            if gameboy.mmu.peek_banked(bank, address).is_none() {
+                return Err(format!("no bank {:02X} at ${:04X}", bank, address));
+            }
+            Ok((bank, address))
+        }
+        None => {
+            let address = parse_address(text, gameboy)?;
+            Ok((gameboy.mmu.mapped_bank(address), address))
+        }
+    }
+}
+
+fn parse_count(text: Option<&&str>) -> Result<u64, String> {
+    match text {
+        Some(text) => text
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| format!("invalid count '{}'", text)),
+        None => Ok(1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // C000 LD A,$42 / C002 INC A / C003 LD ($C100),A / C006 JR C002
+    const PROGRAM: [u8; 8] = [0x3E, 0x42, 0x3C, 0xEA, 0x00, 0xC1, 0x18, 0xFA];
+
+    fn gameboy() -> GameBoy {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        for (i, byte) in PROGRAM.into_iter().enumerate() {
+            gameboy.mmu.poke(0xC000 + i as u16, byte);
+        }
+        gameboy.cpu.registers.pc = 0xC000;
+        gameboy
+    }
+
+    // Output of a command, or its error
+    fn execute(gameboy: &mut GameBoy, line: &str) -> Result<String, String> {
+        let mut out = Vec::new();
+        Debugger::new().execute(gameboy, line, &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn steps_instructions() {
+        let mut gameboy = gameboy();
+        let out = execute(&mut gameboy, "s").unwrap();
+        assert_eq!(gameboy.cpu.registers.pc, 0xC002);
+        assert!(out.contains("INC A"), "{}", out);
+
+        execute(&mut gameboy, "step 3").unwrap();
+        assert_eq!(gameboy.cpu.registers.pc, 0xC002);
+        assert_eq!(gameboy.cpu.registers.a, 0x43);
+    }
+
+    #[test]
+    fn continues_to_a_breakpoint() {
+        let mut gameboy = gameboy();
+        let out = execute(&mut gameboy, "b C006").unwrap();
+        assert_eq!(out, "Breakpoint set at $C006\n");
+        let out = execute(&mut gameboy, "c").unwrap();
+        assert!(out.starts_with("Breakpoint at $C006\n"), "{}", out);
+        assert_eq!(gameboy.cpu.registers.pc, 0xC006);
+
+        // A breakpoint under PC doesn't stop the next run before it has moved
+        execute(&mut gameboy, "b C006 if a == 46").unwrap();
+        assert_eq!(execute(&mut gameboy, "b").unwrap(), "$C006 if a == $46\n");
+        execute(&mut gameboy, "c").unwrap();
+        assert_eq!(
+            (gameboy.cpu.registers.pc, gameboy.cpu.registers.a),
+            (0xC006, 0x46)
+        );
+
+        execute(&mut gameboy, "delete C006").unwrap();
+        assert_eq!(execute(&mut gameboy, "b").unwrap(), "No breakpoints\n");
+    }
+
+    #[test]
+    fn stops_on_a_watched_write() {
+        let mut gameboy = gameboy();
+        execute(&mut gameboy, "watch C100").unwrap();
+        let out = execute(&mut gameboy, "c").unwrap();
+        assert!(
+            out.starts_with("Watchpoint: write $43 at $C100 by $C003\n"),
+            "{}",
+            out
+        );
+        assert_eq!(gameboy.cpu.registers.pc, 0xC006);
+    }
+
+    #[test]
+    fn dumps_memory() {
+        let mut gameboy = gameboy();
+        let out = execute(&mut gameboy, "x C000 8").unwrap();
+        assert_eq!(
+            out,
+            format!(
+                "C000  3E 42 3C EA 00 C1 18 FA{}  |>B<.....|\n",
+                " ".repeat(8 * 3)
+            )
+        );
+
+        execute(&mut gameboy, "write C100 41 42").unwrap();
+        let out = execute(&mut gameboy, "x C100 2").unwrap();
+        assert!(
+            out.starts_with("C100  41 42 ") && out.ends_with("|AB|\n"),
+            "{}",
+            out
+        );
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        let mut gameboy = gameboy();
+        let error = |gameboy: &mut GameBoy, line: &str| execute(gameboy, line).unwrap_err();
+        assert_eq!(error(&mut gameboy, "x"), "x needs an address");
+        assert_eq!(error(&mut gameboy, "step 0"), "invalid count '0'");
+        assert_eq!(error(&mut gameboy, "step two"), "invalid count 'two'");
+        assert_eq!(
+            error(&mut gameboy, "b C006 when a == 1"),
+            "expected 'if', got 'when'"
+        );
+        assert_eq!(
+            error(&mut gameboy, "b C006 if q == 1"),
+            "unknown register 'q'"
+        );
+        assert_eq!(error(&mut gameboy, "delete C006"), "no breakpoint at $C006");
+        assert_eq!(
+            error(&mut gameboy, "write C100"),
+            "write needs at least one byte"
+        );
+        assert_eq!(
+            error(&mut gameboy, "watch C100 rz").split(',').next(),
+            Some("invalid access 'rz'")
+        );
+        assert_eq!(
+            error(&mut gameboy, "jump"),
+            "unknown command 'jump', try help"
+        );
+        // Nothing ran
+        assert_eq!(gameboy.cpu.registers.pc, 0xC000);
+    }
+
+    #[test]
+    fn quits() {
+        let mut gameboy = gameboy();
+        assert_eq!(
+            Debugger::new().execute(&mut gameboy, "q", &mut Vec::new()),
+            Ok(true)
+        );
+        assert_eq!(
+            Debugger::new().execute(&mut gameboy, "   ", &mut Vec::new()),
+            Ok(false)
+        );
+    }
+}
@@ -0,0 +1,221 @@
+/*
+Disassembler, RGBDS syntax. Operand placeholders in the tables:
+    n8   Immediate byte           n16  Immediate word
+    a8   0xFF00 + byte (LDH)      a16  Address
+    e8   Signed offset: the target address for JR, as is for SP+e8
+*/
+
+const REGISTERS: [&str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+const ALU: [&str; 8] = [
+    "ADD A,", "ADC A,", "SUB", "SBC A,", "AND", "XOR", "OR", "CP",
+];
+const SHIFTS: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[rustfmt::skip]
+const OPCODES: [&str; 64] = [
+    // 0x00
+    "NOP", "LD BC, n16", "LD [BC], A", "INC BC", "INC B", "DEC B", "LD B, n8", "RLCA",
+    "LD [a16], SP", "ADD HL, BC", "LD A, [BC]", "DEC BC", "INC C", "DEC C", "LD C, n8", "RRCA",
+    // 0x10
+    "STOP", "LD DE, n16", "LD [DE], A", "INC DE", "INC D", "DEC D", "LD D, n8", "RLA",
+    "JR e8", "ADD HL, DE", "LD A, [DE]", "DEC DE", "INC E", "DEC E", "LD E, n8", "RRA",
+    // 0x20
+    "JR NZ, e8", "LD HL, n16", "LD [HL+], A", "INC HL", "INC H", "DEC H", "LD H, n8", "DAA",
+    "JR Z, e8", "ADD HL, HL", "LD A, [HL+]", "DEC HL", "INC L", "DEC L", "LD L, n8", "CPL",
+    // 0x30
+    "JR NC, e8", "LD SP, n16", "LD [HL-], A", "INC SP", "INC [HL]", "DEC [HL]", "LD [HL], n8", "SCF",
+    "JR C, e8", "ADD HL, SP", "LD A, [HL-]", "DEC SP", "INC A", "DEC A", "LD A, n8", "CCF",
+];
+
+#[rustfmt::skip]
+const HIGH_OPCODES: [&str; 64] = [
+    // 0xC0
+    "RET NZ", "POP BC", "JP NZ, a16", "JP a16", "CALL NZ, a16", "PUSH BC", "ADD A, n8", "RST $00",
+    "RET Z", "RET", "JP Z, a16", "PREFIX", "CALL Z, a16", "CALL a16", "ADC A, n8", "RST $08",
+    // 0xD0
+    "RET NC", "POP DE", "JP NC, a16", "", "CALL NC, a16", "PUSH DE", "SUB n8", "RST $10",
+    "RET C", "RETI", "JP C, a16", "", "CALL C, a16", "", "SBC A, n8", "RST $18",
+    // 0xE0
+    "LDH [a8], A", "POP HL", "LDH [C], A", "", "", "PUSH HL", "AND n8", "RST $20",
+    "ADD SP, e8", "JP HL", "LD [a16], A", "", "", "", "XOR n8", "RST $28",
+    // 0xF0
+    "LDH A, [a8]", "POP AF", "LDH A, [C]", "DI", "", "PUSH AF", "OR n8", "RST $30",
+    "LD HL, SP+e8", "LD SP, HL", "LD A, [a16]", "EI", "", "", "CP n8", "RST $38",
+];
+
+/// One decoded instruction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub text: String,
+    /// Where a jump, call or RST goes, when known without running it.
+    pub target: Option<u16>,
+    /// Memory the instruction reads or writes, for [a16] and LDH [a8] operands.
+    pub operand: Option<u16>,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Address of the instruction that follows it in memory.
+    pub fn next(&self) -> u16 {
+        self.address.wrapping_add(self.len())
+    }
+
+    /// CALL or RST: the next instruction runs after the subroutine returns.
+    pub fn is_call(&self) -> bool {
+        matches!(self.bytes[0], 0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC) || self.bytes[0] & 0xC7 == 0xC7
+    }
+
+    pub fn is_return(&self) -> bool {
+        is_return(self.bytes[0])
+    }
+
+    /// `text` with the target and memory operand replaced by whatever `label` names them.
+    pub fn labeled_text<F: Fn(u16) -> Option<String>>(&self, label: F) -> String {
+        let mut text = self.text.clone();
+        for address in self.target.iter().chain(&self.operand) {
+            if let Some(label) = label(*address) {
+                text = text.replace(&format!("${:04X}", address), &label);
+            }
+        }
+        text
+    }
+}
+
+/// RET, RET cc or RETI.
+pub fn is_return(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+fn template(opcode: u8) -> String {
+    match opcode {
+        0x00..=0x3F => OPCODES[opcode as usize].to_string(),
+        0x76 => String::from("HALT"),
+        0x40..=0x7F => format!(
+            "LD {}, {}",
+            REGISTERS[(opcode >> 3 & 7) as usize],
+            REGISTERS[(opcode & 7) as usize]
+        ),
+        0x80..=0xBF => format!(
+            "{} {}",
+            ALU[(opcode >> 3 & 7) as usize],
+            REGISTERS[(opcode & 7) as usize]
+        ),
+        _ => HIGH_OPCODES[(opcode - 0xC0) as usize].to_string(),
+    }
+}
+
+fn cb_template(opcode: u8) -> String {
+    let register = REGISTERS[(opcode & 7) as usize];
+    let bit = opcode >> 3 & 7;
+    match opcode >> 6 {
+        0 => format!("{} {}", SHIFTS[bit as usize], register),
+        1 => format!("BIT {}, {}", bit, register),
+        2 => format!("RES {}, {}", bit, register),
+        _ => format!("SET {}, {}", bit, register),
+    }
+}
+
+/// Decodes the instruction at `address`. `read` must not have side effects: it is called on
+/// whatever memory the instruction happens to cover.
+pub fn disassemble<F: Fn(u16) -> u8>(read: F, address: u16) -> Instruction {
+    let byte = |offset: u16| read(address.wrapping_add(offset));
+    let opcode = byte(0);
+    let mut bytes = vec![opcode];
+    let mut target = None;
+    let mut operand = None;
+
+    let text = match opcode {
+        0xCB => {
+            bytes.push(byte(1));
+            cb_template(bytes[1])
+        }
+        // STOP is followed by a byte the CPU skips over
+        0x10 => {
+            bytes.push(byte(1));
+            String::from("STOP")
+        }
+        _ => {
+            let template = template(opcode);
+            if template.is_empty() {
+                format!("DB ${:02X}", opcode)
+            } else if template.contains("n16") || template.contains("a16") {
+                let word = u16::from_le_bytes([byte(1), byte(2)]);
+                bytes.extend_from_slice(&[byte(1), byte(2)]);
+                // JP and CALL
+                if matches!(opcode & 0xE7, 0xC2 | 0xC4) || matches!(opcode, 0xC3 | 0xCD) {
+                    target = Some(word);
+                } else if template.contains("a16") {
+                    operand = Some(word);
+                }
+                template
+                    .replace("n16", &format!("${:04X}", word))
+                    .replace("a16", &format!("${:04X}", word))
+            } else if template.contains("n8") {
+                bytes.push(byte(1));
+                template.replace("n8", &format!("${:02X}", byte(1)))
+            } else if template.contains("a8") {
+                bytes.push(byte(1));
+                operand = Some(0xFF00 | byte(1) as u16);
+                template.replace("a8", &format!("$FF{:02X}", byte(1)))
+            } else if template.starts_with("JR") {
+                bytes.push(byte(1));
+                let offset = byte(1) as i8;
+                let jump = address.wrapping_add(2).wrapping_add(offset as u16);
+                target = Some(jump);
+                template.replace("e8", &format!("${:04X}", jump))
+            } else if template.contains("e8") {
+                bytes.push(byte(1));
+                let offset = byte(1) as i8;
+                let sign = if offset < 0 { '-' } else { '+' };
+                let text = format!("{}${:02X}", sign, offset.unsigned_abs());
+                template
+                    .replace("SP+e8", &format!("SP{}", text))
+                    .replace("e8", &text)
+            } else {
+                if opcode & 0xC7 == 0xC7 {
+                    target = Some((opcode & 0x38) as u16);
+                }
+                template
+            }
+        }
+    };
+
+    Instruction {
+        address,
+        bytes,
+        text,
+        target,
+        operand,
+    }
+}
+
+/// Start addresses of up to `count` instructions ending at (and including) the one at `address`.
+/// Instructions don't have a fixed length, so this decodes forward from a little before and
+/// keeps the first path that lands on `address`.
+pub fn preceding<F: Fn(u16) -> u8>(read: F, address: u16, count: usize) -> Vec<u16> {
+    let window = (count as u16).saturating_mul(3);
+    for back in (1..=window).rev() {
+        let start = address.wrapping_sub(back);
+        let mut addresses = Vec::new();
+        let mut pc = start;
+        while pc.wrapping_sub(start) < back {
+            addresses.push(pc);
+            pc = disassemble(&read, pc).next();
+        }
+        if pc == address {
+            addresses.push(address);
+            let skip = addresses.len().saturating_sub(count);
+            return addresses.split_off(skip);
+        }
+    }
+    vec![address]
+}
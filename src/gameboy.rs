@@ -0,0 +1,476 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::breakpoint::Stop;
+use crate::cpu::CPU;
+use crate::gpu::Screen;
+use crate::joypad::Button;
+use crate::mmu::MMU;
+use crate::model::Model;
+use crate::movie::Movie;
+use crate::rewind::Rewind;
+use crate::savestate::{self, SaveState, StateReader, StateWriter};
+use crate::serial::{Link, SerialSink};
+use crate::symbols::Symbols;
+
+// T-cycles = Clock cycles. 1 M-cycle = 4 T-cycles
+pub const CYCLES_PER_FRAME: u32 = 70224 / 4; // M-cycles.
+pub const FRAME_TIME: Duration = Duration::from_micros((1_000_000.0 / 59.7) as u64);
+
+const TEST_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61]; // Test ROMs reporting in cartridge RAM
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    Failed,
+}
+
+pub struct GameBoy {
+    pub cpu: CPU,
+    pub mmu: MMU,
+    pub gpu: Screen,
+    pub model: Model,
+    pub frame_count: u64,
+    pub symbols: Symbols, // Labels for the disassembler, tracer and debuggers
+    frame_cycles: u32,    // M-cycles into the current frame
+    gpu_dots: i32,
+    trace: Option<Box<dyn Write>>,
+    playback: Option<Movie>,
+    recording: Option<Movie>,
+    rewind: Option<Rewind>,
+    stop: Option<Stop>, // Breakpoint or watchpoint hit, until taken
+}
+
+impl GameBoy {
+    pub fn new(model: Model) -> Self {
+        GameBoy {
+            cpu: CPU::new(model),
+            mmu: MMU::new(model),
+            gpu: Screen::new(),
+            model,
+            frame_count: 0,
+            symbols: Symbols::new(),
+            frame_cycles: 0,
+            gpu_dots: 0,
+            trace: None,
+            playback: None,
+            recording: None,
+            rewind: None,
+            stop: None,
+        }
+    }
+
+    pub fn load_rom<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.mmu.read_rom(path)
+    }
+
+    /// Maps a DMG (256 bytes) or CGB (2304 bytes) boot ROM and restarts the CPU at 0x0000.
+    pub fn load_boot_rom<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.mmu.load_boot_rom(path)?;
+        self.cpu = CPU::new_boot_rom();
+        Ok(())
+    }
+
+    /// Loads an RGBDS .sym file, replacing any labels loaded before.
+    pub fn load_symbols<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.symbols = Symbols::load(path)?;
+        Ok(())
+    }
+
+    /// Label exactly at `address`, in the banks mapped now.
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.symbols.label(self.mmu.mapped_bank(address), address)
+    }
+
+    /// `address` as the closest label before it, `Label` or `Label+$N`, in the banks mapped now.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        self.symbols
+            .describe(self.mmu.mapped_bank(address), address)
+    }
+
+    /// Shadow call stack with labels, innermost first: where the CPU is, then every call site.
+    pub fn write_stack_trace<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let pc = self.cpu.registers.pc;
+        let location = |bank: u16, address: u16| match self.symbols.describe(bank, address) {
+            Some(label) => format!("${:04X} <{}>", address, label),
+            None => format!("${:04X}", address),
+        };
+        writeln!(out, "#0  {}", location(self.mmu.mapped_bank(pc), pc))?;
+        for (i, frame) in self.cpu.call_stack.frames().iter().rev().enumerate() {
+            writeln!(
+                out,
+                "#{:<2} {} {}",
+                i + 1,
+                location(frame.bank, frame.call_site),
+                frame
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Connects the serial port to another emulator, see `serial::link`.
+    pub fn set_link(&mut self, link: Box<dyn Link>) {
+        self.mmu.serial.set_link(link);
+    }
+
+    /// Replaces the default sink, which keeps every byte for `serial_output`.
+    pub fn set_serial_sink(&mut self, sink: Box<dyn SerialSink>) {
+        self.mmu.serial_sink = sink;
+    }
+
+    /// Bytes sent through the serial port so far, as kept by the sink.
+    pub fn serial_output(&self) -> &[u8] {
+        self.mmu.serial_sink.output()
+    }
+
+    /// Logs the CPU state before every instruction, one line per instruction.
+    pub fn set_trace(&mut self, out: Box<dyn Write>) {
+        self.trace = Some(out);
+    }
+
+    /// Executes one instruction (or interrupt dispatch) and advances the PPU. Returns M-cycles.
+    /// Frames end inside `step`, so stepping one instruction at a time keeps the frame count,
+    /// movie input and rewind snapshots as they would be with `run_frame`.
+    pub fn step(&mut self) -> io::Result<u32> {
+        if self.frame_cycles == 0 {
+            self.start_frame();
+        }
+
+        if let Some(out) = self.trace.as_mut() {
+            if !self.cpu.halt_flag {
+                let label = self.symbols.label(
+                    self.mmu.mapped_bank(self.cpu.registers.pc),
+                    self.cpu.registers.pc,
+                );
+                trace_line(out, &self.cpu, &self.mmu, label)?;
+            }
+        }
+
+        let step = self.cpu.step(&mut self.mmu);
+        let cycles = step.cycles;
+        if step.stop.is_some() {
+            self.stop = step.stop;
+        }
+        self.mmu.apu.step(cycles);
+        self.mmu.step_serial(cycles)?;
+
+        self.gpu_dots -= cycles as i32 * 4;
+        if self.gpu_dots <= 0 {
+            // PPU modes that don't consume dots yet would otherwise drive the counter down forever
+            self.gpu_dots = (self.gpu_dots + self.gpu.step(&mut self.mmu) as i32).max(0);
+        }
+
+        self.frame_cycles += cycles;
+        if self.frame_cycles >= CYCLES_PER_FRAME {
+            self.end_frame()?;
+        }
+        Ok(cycles)
+    }
+
+    /// Runs until the current frame ends, or a breakpoint or watchpoint stops the CPU
+    /// (see `take_stop`).
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        let frame = self.frame_count;
+        loop {
+            self.step()?;
+            if self.frame_count != frame || self.stop.is_some() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Where the CPU stopped since the last call, if it hit a breakpoint or watchpoint.
+    pub fn take_stop(&mut self) -> Option<Stop> {
+        self.stop.take()
+    }
+
+    /// M-cycles run so far in the current frame.
+    pub fn frame_cycles(&self) -> u32 {
+        self.frame_cycles
+    }
+
+    fn start_frame(&mut self) {
+        if let Some(movie) = &self.playback {
+            // Buttons are released once the movie runs out
+            let input = movie
+                .frames
+                .get(self.frame_count as usize)
+                .copied()
+                .unwrap_or(0);
+            self.set_buttons(input);
+        }
+        // What the joypad got, played back or not. A zero-cycle step (STOP) at the start of a
+        // frame comes back here
+        if let Some(movie) = self.recording.as_mut() {
+            if movie.frames.len() as u64 == self.frame_count {
+                movie.frames.push(self.mmu.joypad.pressed);
+            }
+        }
+    }
+
+    // Cycles past the end of the frame are dropped, the next one starts from zero
+    fn end_frame(&mut self) -> io::Result<()> {
+        self.frame_cycles = 0;
+        self.frame_count += 1;
+
+        if let Some(mut rewind) = self.rewind.take() {
+            rewind.record(self);
+            self.rewind = Some(rewind);
+        }
+
+        if let Some(out) = self.trace.as_mut() {
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Snapshot of the whole machine, see `savestate` for the format. The ROM must be loaded
+    /// again before the state can be restored.
+    pub fn save_state(&self) -> Vec<u8> {
+        savestate::encode(self.model, self.mmu.rom_crc32, &self.state_payload())
+    }
+
+    /// Restores a `save_state` snapshot. States for another model, ROM or format version are
+    /// rejected and leave the machine as it was, and so are states that fail to load halfway.
+    pub fn load_state(&mut self, data: &[u8]) -> io::Result<()> {
+        let payload = savestate::decode(data, self.model, self.mmu.rom_crc32)?;
+        // Components load in place, so undo whatever a bad payload got to overwrite
+        let snapshot = self.state_payload();
+        let call_stack = self.cpu.call_stack.clone();
+        let result = self.load_payload(payload);
+        if result.is_err() {
+            self.load_payload(&snapshot)
+                .expect("the running machine's own state must load");
+            self.cpu.call_stack = call_stack;
+        }
+        result
+    }
+
+    fn state_payload(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.cpu.save_state(&mut w);
+        self.mmu.save_state(&mut w);
+        self.gpu.save_state(&mut w);
+        w.u64(self.frame_count);
+        w.u32(self.frame_cycles);
+        w.i32(self.gpu_dots);
+        w.into_bytes()
+    }
+
+    fn load_payload(&mut self, payload: &[u8]) -> io::Result<()> {
+        let mut r = StateReader::new(payload);
+        self.cpu.load_state(&mut r)?;
+        self.mmu.load_state(&mut r)?;
+        self.gpu.load_state(&mut r)?;
+        self.frame_count = r.u64()?;
+        self.frame_cycles = r.u32()?;
+        self.gpu_dots = r.i32()?;
+        if !r.is_empty() {
+            return Err(savestate::invalid("save state is corrupt"));
+        }
+        Ok(())
+    }
+
+    /// Keeps snapshots from now on so that `step_back` can go back in time.
+    pub fn set_rewind(&mut self, rewind: Option<Rewind>) {
+        self.rewind = rewind;
+    }
+
+    pub fn rewind(&self) -> Option<&Rewind> {
+        self.rewind.as_ref()
+    }
+
+    /// Restores the latest snapshot before the current frame. Returns false if there is none.
+    pub fn step_back(&mut self) -> io::Result<bool> {
+        let Some(mut rewind) = self.rewind.take() else {
+            return Ok(false);
+        };
+        let result = rewind.step_back(self);
+        self.rewind = Some(rewind);
+        result
+    }
+
+    pub fn save_state_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.save_state())
+    }
+
+    pub fn load_state_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.load_state(&fs::read(path)?)
+    }
+
+    /// Presses or releases a button, raising the joypad interrupt as the hardware would.
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        self.mmu.set_button(button, pressed);
+    }
+
+    /// Sets every button at once from a `Joypad::pressed`-style mask.
+    pub fn set_buttons(&mut self, pressed: u8) {
+        for button in Button::ALL {
+            self.set_button(button, pressed & (1 << button as u8) != 0);
+        }
+    }
+
+    /// Starts logging the joypad state of every frame, including the input of a movie being
+    /// played back. Movies always start at power on.
+    pub fn start_recording(&mut self) -> Result<(), String> {
+        if self.frame_count != 0 {
+            return Err(String::from("movies must be recorded from power on"));
+        }
+        self.recording = Some(Movie::new(self.model, self.mmu.rom_crc32));
+        Ok(())
+    }
+
+    /// Replays a recorded movie from power on, overriding any other input.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if self.frame_count != 0 {
+            return Err(String::from("movies must be played from power on"));
+        }
+        if movie.model != self.model {
+            return Err(format!(
+                "movie was recorded on {}, running {}",
+                movie.model, self.model
+            ));
+        }
+        if movie.rom_crc32 != self.mmu.rom_crc32 {
+            return Err(format!(
+                "movie was recorded with ROM CRC32 {:08X}, loaded ROM is {:08X}",
+                movie.rom_crc32, self.mmu.rom_crc32
+            ));
+        }
+        self.playback = Some(movie);
+        Ok(())
+    }
+
+    /// Stops recording, returning the movie recorded.
+    pub fn take_recording(&mut self) -> Option<Movie> {
+        self.recording.take()
+    }
+
+    pub fn movie_finished(&self) -> bool {
+        self.playback
+            .as_ref()
+            .is_some_and(|movie| self.frame_count >= movie.frames.len() as u64)
+    }
+
+    /// Result reported by Blargg-style test ROMs, if any yet: through the serial port, or in
+    /// cartridge RAM for the ones that report there.
+    pub fn test_result(&self) -> Option<TestResult> {
+        let text = String::from_utf8_lossy(self.serial_output());
+        if text.contains("Failed") {
+            Some(TestResult::Failed)
+        } else if text.contains("Passed") {
+            Some(TestResult::Passed)
+        } else {
+            self.memory_test_report().map(|(result, _)| result)
+        }
+    }
+
+    /// Result and text of a test ROM reporting in cartridge RAM, once it has finished.
+    /// 0xA001-0xA003 hold DE B0 61 once the test has started, 0xA000 is 0x80 while it runs
+    /// (0x81 if it needs a reset) and then the result, 0 for passed. The text the test printed
+    /// follows from 0xA004, zero-terminated.
+    pub fn memory_test_report(&self) -> Option<(TestResult, String)> {
+        let ram = self.mmu.cartridge_ram();
+        if ram[1..4] != TEST_SIGNATURE || matches!(ram[0], 0x80 | 0x81) {
+            return None;
+        }
+        let text = &ram[4..];
+        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+        let text = String::from_utf8_lossy(&text[..end]).into_owned();
+        let result = match ram[0] {
+            0 => TestResult::Passed,
+            _ => TestResult::Failed,
+        };
+        Some((result, text))
+    }
+}
+
+impl Default for GameBoy {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
+}
+
+// gameboy-doctor format. Labels go at the end, so traces without symbols still compare
+fn trace_line(
+    out: &mut Box<dyn Write>,
+    cpu: &CPU,
+    mmu: &MMU,
+    label: Option<&str>,
+) -> io::Result<()> {
+    let r = &cpu.registers;
+    write!(
+        out,
+        "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+        r.a,
+        r.f,
+        r.b,
+        r.c,
+        r.d,
+        r.e,
+        r.h,
+        r.l,
+        r.sp,
+        r.pc,
+        mmu.peek(r.pc),
+        mmu.peek(r.pc.wrapping_add(1)),
+        mmu.peek(r.pc.wrapping_add(2)),
+        mmu.peek(r.pc.wrapping_add(3)),
+    )?;
+    match label {
+        Some(label) => writeln!(out, " ; {}", label),
+        None => writeln!(out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A state from another machine with the same model and ROM, with `patch` applied
+    fn state(gameboy: &GameBoy, patch: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut other = GameBoy::new(gameboy.model);
+        other.cpu.registers.a = 0x42;
+        other.mmu.poke(0xC000, 0x99);
+        other.frame_count = 7;
+        let mut payload = other.state_payload();
+        patch(&mut payload);
+        savestate::encode(gameboy.model, gameboy.mmu.rom_crc32, &payload)
+    }
+
+    #[test]
+    fn loads_a_state() {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        gameboy.load_state(&state(&gameboy, |_| {})).unwrap();
+        assert_eq!(gameboy.cpu.registers.a, 0x42);
+        assert_eq!(gameboy.mmu.peek(0xC000), 0x99);
+        assert_eq!(gameboy.frame_count, 7);
+    }
+
+    #[test]
+    fn a_state_rejected_halfway_leaves_the_machine_as_it_was() {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        let before = gameboy.save_state();
+
+        // The CPU and MMU load before the missing bytes are noticed
+        let truncated = state(&gameboy, |payload| {
+            payload.truncate(payload.len() - 2);
+        });
+        assert!(gameboy.load_state(&truncated).is_err());
+        assert!(gameboy.save_state() == before);
+
+        let mut cpu_mmu = StateWriter::new();
+        gameboy.cpu.save_state(&mut cpu_mmu);
+        gameboy.mmu.save_state(&mut cpu_mmu);
+        let ppu_mode = cpu_mmu.into_bytes().len() + gameboy.gpu.pixels.len();
+        let bad_mode = state(&gameboy, |payload| payload[ppu_mode] = 4);
+        assert!(gameboy.load_state(&bad_mode).is_err());
+        assert!(gameboy.save_state() == before);
+
+        let trailing = state(&gameboy, |payload| payload.push(0));
+        assert!(gameboy.load_state(&trailing).is_err());
+        assert!(gameboy.save_state() == before);
+    }
+}
@@ -0,0 +1,315 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::apu::CPU_FREQUENCY;
+use crate::gameboy::{GameBoy, CYCLES_PER_FRAME};
+use crate::model::Model;
+
+pub const HEADER_LENGTH: usize = 0x70;
+
+const MAGIC: &[u8; 3] = b"GBS";
+const TIMA: u16 = 0xFF05;
+const TMA: u16 = 0xFF06;
+const TAC: u16 = 0xFF07;
+const IE: u16 = 0xFFFF;
+
+// Routines return here: "JR -2" keeps the CPU (and DIV, so the frame sequencer) running until the next call
+const IDLE_ADDRESS: u16 = 0x0080;
+const IDLE_LOOP: [u8; 2] = [0x18, 0xFE];
+
+/*
+GBS header (0x70 bytes, little-endian), followed by the music data
+0x00: "GBS"
+0x03: Version (1)
+0x04: Number of songs
+0x05: First song, 1-based
+0x06: Load address. Data is mapped from here, usually 0x0400 or higher
+0x08: Init address. Called with the 0-based song number in A
+0x0A: Play address. Called at the VBlank rate, or the timer rate if TAC bit 2 is set
+0x0C: Stack pointer
+0x0E: TMA
+0x0F: TAC. bit 7: CGB double speed
+0x10: Title, 32 bytes
+0x30: Author, 32 bytes
+0x50: Copyright, 32 bytes
+*/
+
+/// Music ripped from a cartridge: the sound driver and its data, with entry points to drive it.
+#[derive(Clone)]
+pub struct Gbs {
+    pub version: u8,
+    pub song_count: u8,
+    pub first_song: u8, // 1-based
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub stack_pointer: u16,
+    pub timer_modulo: u8,
+    pub timer_control: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    pub data: Vec<u8>,
+}
+
+impl Gbs {
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        Self::parse(&bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(bytes: &[u8]) -> Result<Self, String> {
+        if bytes.len() < HEADER_LENGTH || &bytes[..3] != MAGIC {
+            return Err(String::from("not a GBS file"));
+        }
+        let word = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let text = |offset: usize| {
+            let field = &bytes[offset..offset + 32];
+            let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..end]).into_owned()
+        };
+
+        let gbs = Gbs {
+            version: bytes[0x03],
+            song_count: bytes[0x04],
+            first_song: bytes[0x05],
+            load_address: word(0x06),
+            init_address: word(0x08),
+            play_address: word(0x0A),
+            stack_pointer: word(0x0C),
+            timer_modulo: bytes[0x0E],
+            timer_control: bytes[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: bytes[HEADER_LENGTH..].to_vec(),
+        };
+
+        if gbs.version != 1 {
+            return Err(format!("unsupported GBS version {}", gbs.version));
+        }
+        if gbs.song_count == 0 {
+            return Err(String::from("GBS file has no songs"));
+        }
+        if gbs.load_address < IDLE_ADDRESS + IDLE_LOOP.len() as u16 || gbs.load_address >= 0x8000 {
+            return Err(format!("invalid load address 0x{:04X}", gbs.load_address));
+        }
+        Ok(gbs)
+    }
+
+    /// Cartridge image with the data at the load address. RST vectors jump to the load address
+    /// plus the vector, as GBS drivers expect.
+    pub fn rom(&self) -> Vec<u8> {
+        let load = self.load_address as usize;
+        let len = (load + self.data.len())
+            .max(0x8000)
+            .next_multiple_of(0x4000);
+        let mut rom = vec![0xFF; len];
+
+        for vector in (0x00..0x40).step_by(8) {
+            let target = (self.load_address + vector as u16).to_le_bytes();
+            rom[vector..vector + 3].copy_from_slice(&[0xC3, target[0], target[1]]);
+            // JP nn
+        }
+        let idle = IDLE_ADDRESS as usize;
+        rom[idle..idle + IDLE_LOOP.len()].copy_from_slice(&IDLE_LOOP);
+        rom[load..load + self.data.len()].copy_from_slice(&self.data);
+        rom
+    }
+
+    /// Every song, in the order a player goes through them: from the first song on, wrapping
+    /// around. A first song out of range counts as song 1.
+    pub fn songs(&self) -> impl Iterator<Item = u8> {
+        let count = self.song_count as u16;
+        let first = if (1..=count).contains(&(self.first_song as u16)) {
+            self.first_song as u16
+        } else {
+            1
+        };
+        (0..count).map(move |i| ((first - 1 + i) % count + 1) as u8)
+    }
+
+    pub fn uses_timer(&self) -> bool {
+        self.timer_control & 0x04 != 0
+    }
+
+    /// M-cycles between calls to the play routine.
+    pub fn play_period(&self) -> u32 {
+        if !self.uses_timer() {
+            return CYCLES_PER_FRAME;
+        }
+        // TAC input clock, in M-cycles per TIMA increment
+        let divider = match self.timer_control & 0x03 {
+            0 => CPU_FREQUENCY / 4 / 4096,
+            1 => CPU_FREQUENCY / 4 / 262_144,
+            2 => CPU_FREQUENCY / 4 / 65_536,
+            _ => CPU_FREQUENCY / 4 / 16_384,
+        };
+        let period = (256 - self.timer_modulo as u32) * divider;
+        // Drivers built for double speed expect twice the rate
+        if self.timer_control & 0x80 != 0 {
+            (period / 2).max(1)
+        } else {
+            period
+        }
+    }
+}
+
+/// Runs a GBS driver headlessly: init once for the song, then play at the driver's rate.
+pub struct GbsPlayer {
+    pub gameboy: GameBoy,
+    pub gbs: Gbs,
+    period: u32,
+    cycles: u32, // M-cycles since the last call to play
+}
+
+impl GbsPlayer {
+    /// Starts `song` (1-based) on a fresh machine.
+    pub fn new(gbs: Gbs, model: Model, song: u8) -> Result<Self, String> {
+        if song == 0 || song > gbs.song_count {
+            return Err(format!(
+                "song {} out of range, the file has {}",
+                song, gbs.song_count
+            ));
+        }
+
+        let mut gameboy = GameBoy::new(model);
+        gameboy.mmu.load_rom_data(gbs.rom());
+        gameboy.mmu.rom_bank_switching = true;
+        // Play is called by the player, never through interrupts
+        gameboy.cpu.ime = false;
+        gameboy.mmu.write_byte(IE, 0);
+        gameboy.mmu.write_byte(TMA, gbs.timer_modulo);
+        gameboy.mmu.write_byte(TIMA, gbs.timer_modulo);
+        gameboy.mmu.write_byte(TAC, gbs.timer_control & 0x07);
+
+        let mut player = GbsPlayer {
+            period: gbs.play_period(),
+            gbs,
+            gameboy,
+            cycles: 0,
+        };
+        player.gameboy.cpu.registers.sp = player.gbs.stack_pointer;
+        player.gameboy.cpu.registers.a = song - 1;
+        player.call(player.gbs.init_address);
+        Ok(player)
+    }
+
+    fn call(&mut self, address: u16) {
+        let cpu = &mut self.gameboy.cpu;
+        cpu.registers.pc = IDLE_ADDRESS;
+        cpu.rst(address, &mut self.gameboy.mmu);
+    }
+
+    // Back in the idle loop once the routine returns
+    fn is_idle(&self) -> bool {
+        (IDLE_ADDRESS..IDLE_ADDRESS + IDLE_LOOP.len() as u16)
+            .contains(&self.gameboy.cpu.registers.pc)
+    }
+
+    /// Runs one frame's worth of cycles. A play call that is still running when the next one is
+    /// due just delays it.
+    pub fn run_frame(&mut self) -> io::Result<()> {
+        let frame = self.gameboy.frame_count;
+        while self.gameboy.frame_count == frame {
+            if self.cycles >= self.period && self.is_idle() {
+                // Calls missed while play was still running are dropped, not caught up on
+                self.cycles %= self.period;
+                self.call(self.gbs.play_address);
+            }
+            self.cycles += self.gameboy.step()?;
+        }
+        Ok(())
+    }
+}
+
+/// True if the file starts with the GBS signature.
+pub fn is_gbs<P: AsRef<Path>>(path: P) -> bool {
+    let mut magic = [0; 3];
+    fs::File::open(path)
+        .and_then(|mut file| io::Read::read_exact(&mut file, &mut magic))
+        .is_ok()
+        && &magic == MAGIC
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_LENGTH];
+        bytes[..6].copy_from_slice(&[b'G', b'B', b'S', 1, 12, 3]);
+        bytes[0x06..0x0E].copy_from_slice(&[0x00, 0x04, 0x10, 0x04, 0x20, 0x04, 0xFE, 0xDF]);
+        bytes[0x10..0x15].copy_from_slice(b"Title");
+        bytes[0x30..0x50].copy_from_slice(&[b'A'; 32]); // Fills the field, no terminator
+        bytes.extend_from_slice(&[0xC9, 0xC9]);
+        bytes
+    }
+
+    #[test]
+    fn parses_the_header() {
+        let gbs = Gbs::parse(&header()).unwrap();
+        assert_eq!((gbs.version, gbs.song_count, gbs.first_song), (1, 12, 3));
+        assert_eq!(
+            (gbs.load_address, gbs.init_address, gbs.play_address),
+            (0x0400, 0x0410, 0x0420)
+        );
+        assert_eq!(gbs.stack_pointer, 0xDFFE);
+        assert_eq!(gbs.title, "Title");
+        assert_eq!(gbs.author, "A".repeat(32));
+        assert_eq!(gbs.copyright, "");
+        assert_eq!(gbs.data, [0xC9, 0xC9]);
+        assert!(!gbs.uses_timer());
+        assert_eq!(gbs.play_period(), CYCLES_PER_FRAME);
+    }
+
+    #[test]
+    fn songs_start_at_the_first_song() {
+        let mut gbs = Gbs::parse(&header()).unwrap();
+        let songs: Vec<u8> = gbs.songs().collect();
+        assert_eq!(songs, [3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 1, 2]);
+
+        gbs.first_song = 0;
+        assert!(gbs.songs().eq(1..=12));
+        (gbs.song_count, gbs.first_song) = (255, 255);
+        assert!(gbs.songs().eq([255].into_iter().chain(1..=254)));
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        let parse = |change: fn(&mut Vec<u8>)| {
+            let mut bytes = header();
+            change(&mut bytes);
+            Gbs::parse(&bytes).err().unwrap()
+        };
+        assert_eq!(parse(|b| b.truncate(HEADER_LENGTH - 1)), "not a GBS file");
+        assert_eq!(parse(|b| b[0] = b'X'), "not a GBS file");
+        assert_eq!(parse(|b| b[3] = 2), "unsupported GBS version 2");
+        assert_eq!(parse(|b| b[4] = 0), "GBS file has no songs");
+        assert_eq!(parse(|b| b[7] = 0x00), "invalid load address 0x0000");
+        assert_eq!(parse(|b| b[7] = 0x80), "invalid load address 0x8000");
+    }
+
+    #[test]
+    fn plays_at_the_timer_rate() {
+        let mut bytes = header();
+        bytes[0x0E] = 0xC0; // 64 ticks
+        bytes[0x0F] = 0x04; // 4096 Hz, 256 M-cycles a tick
+        let gbs = Gbs::parse(&bytes).unwrap();
+        assert_eq!(gbs.play_period(), 64 * 256);
+
+        bytes[0x0F] = 0x84;
+        assert_eq!(Gbs::parse(&bytes).unwrap().play_period(), 32 * 256);
+    }
+
+    #[test]
+    fn maps_the_data_behind_the_rst_vectors() {
+        let rom = Gbs::parse(&header()).unwrap().rom();
+        assert_eq!(rom.len(), 0x8000);
+        assert_eq!(rom[0x00..0x03], [0xC3, 0x00, 0x04]);
+        assert_eq!(rom[0x38..0x3B], [0xC3, 0x38, 0x04]);
+        assert_eq!(rom[0x80..0x82], IDLE_LOOP);
+        assert_eq!(rom[0x0400..0x0403], [0xC9, 0xC9, 0xFF]);
+    }
+}
@@ -0,0 +1,488 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use crate::breakpoint::{Access, Breakpoint, Stop, Watchpoint};
+use crate::gameboy::GameBoy;
+
+/*
+GDB remote serial protocol. Packets are "$<data>#<checksum>", the checksum being the sum of the
+data bytes modulo 256 in two hex digits. Each side acknowledges with '+' (or '-' to get it
+again) until the client asks for QStartNoAckMode. A bare 0x03 byte interrupts the target.
+
+Registers, 16 bits each, little-endian, in this order: AF BC DE HL SP PC. gdb doesn't know the
+SM83, so the layout is also served as target.xml.
+
+Supported: ? g G p P m M c s Z0-Z4 z0-z4 k D, plus the queries gdb needs to connect.
+*/
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+const PACKET_SIZE: usize = 0x1000;
+const INTERRUPT_POLL: u32 = 0x4000; // Steps between checks for ^C while running
+const REGISTERS: usize = 6;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rustboy.sm83">
+    <reg name="af" bitsize="16" type="int"/>
+    <reg name="bc" bitsize="16" type="int"/>
+    <reg name="de" bitsize="16" type="int"/>
+    <reg name="hl" bitsize="16" type="int"/>
+    <reg name="sp" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+enum Request {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+    Kill,
+}
+
+/// Waits for a client on `address` ("host:port") and serves it until it detaches or kills
+/// the target. The machine is stopped whenever the client isn't running it.
+pub fn serve(gameboy: &mut GameBoy, address: &str) -> io::Result<()> {
+    let (stream, _) = TcpListener::bind(address)?.accept()?;
+    stream.set_nodelay(true)?;
+    Session {
+        stream,
+        no_ack: false,
+    }
+    .run(gameboy)
+}
+
+struct Session {
+    stream: TcpStream,
+    no_ack: bool,
+}
+
+impl Session {
+    fn run(&mut self, gameboy: &mut GameBoy) -> io::Result<()> {
+        loop {
+            let Some(packet) = self.receive()? else {
+                return Ok(()); // Client gone
+            };
+            match self.handle(gameboy, &packet) {
+                Request::Reply(reply) => self.send(&reply)?,
+                Request::Continue => {
+                    let reply = self.resume(gameboy, false)?;
+                    self.send(&reply)?;
+                }
+                Request::Step => {
+                    let reply = self.resume(gameboy, true)?;
+                    self.send(&reply)?;
+                }
+                Request::Detach => {
+                    self.send("OK")?;
+                    return Ok(());
+                }
+                Request::Kill => return Ok(()),
+            }
+        }
+    }
+
+    // Next packet, None at the end of the connection. ^C while stopped is answered right away
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        let mut byte = [0];
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            match byte[0] {
+                b'$' => break,
+                0x03 => self.send(&stop_reply(SIGINT))?,
+                _ => {} // Acks, and noise between packets
+            }
+        }
+
+        let mut data = Vec::new();
+        loop {
+            if self.stream.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+            if byte[0] == b'#' {
+                break;
+            }
+            data.push(byte[0]);
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum)?;
+
+        let expected = u8::from_str_radix(&String::from_utf8_lossy(&checksum), 16).ok();
+        if !self.no_ack {
+            let valid = expected == Some(checksum_of(&data));
+            self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            if !valid {
+                return self.receive();
+            }
+        }
+        Ok(Some(unescape(&data)))
+    }
+
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())?;
+        self.stream.flush()
+    }
+
+    fn handle(&mut self, gameboy: &mut GameBoy, packet: &str) -> Request {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => stop_reply(SIGTRAP),
+            Some(b'g') => {
+                let mut reply = String::new();
+                for register in 0..REGISTERS {
+                    reply += &hex_word(read_register(gameboy, register));
+                }
+                reply
+            }
+            Some(b'G') => {
+                let bytes = from_hex(&packet[1..]);
+                if bytes.len() < REGISTERS * 2 {
+                    return Request::Reply(String::from("E01"));
+                }
+                for (register, value) in bytes.chunks_exact(2).take(REGISTERS).enumerate() {
+                    let value = u16::from_le_bytes([value[0], value[1]]);
+                    write_register(gameboy, register, value);
+                }
+                String::from("OK")
+            }
+            Some(b'p') => match usize::from_str_radix(&packet[1..], 16) {
+                Ok(register) if register < REGISTERS => hex_word(read_register(gameboy, register)),
+                _ => String::from("E01"),
+            },
+            Some(b'P') => {
+                let parsed = packet[1..].split_once('=').and_then(|(register, value)| {
+                    let register = usize::from_str_radix(register, 16).ok()?;
+                    let bytes = from_hex(value);
+                    (register < REGISTERS && bytes.len() == 2)
+                        .then(|| (register, u16::from_le_bytes([bytes[0], bytes[1]])))
+                });
+                match parsed {
+                    Some((register, value)) => {
+                        write_register(gameboy, register, value);
+                        String::from("OK")
+                    }
+                    None => String::from("E01"),
+                }
+            }
+            Some(b'm') => match parse_range(&packet[1..]) {
+                Some((address, length)) => (0..length.min(PACKET_SIZE / 2))
+                    .map(|i| {
+                        let address = address.wrapping_add(i as u16);
+                        format!("{:02x}", gameboy.mmu.peek(address))
+                    })
+                    .collect(),
+                None => String::from("E01"),
+            },
+            Some(b'M') => {
+                let parsed = packet[1..]
+                    .split_once(':')
+                    .and_then(|(range, data)| Some((parse_range(range)?, from_hex(data))));
+                match parsed {
+                    Some(((address, length), bytes)) if bytes.len() == length => {
+                        for (i, byte) in bytes.into_iter().enumerate() {
+                            gameboy.mmu.poke(address.wrapping_add(i as u16), byte);
+                        }
+                        String::from("OK")
+                    }
+                    _ => String::from("E01"),
+                }
+            }
+            // Both may give an address to resume at
+            Some(b'c' | b's') => {
+                if let Ok(address) = u16::from_str_radix(&packet[1..], 16) {
+                    gameboy.cpu.registers.pc = address;
+                }
+                return if packet.starts_with('c') {
+                    Request::Continue
+                } else {
+                    Request::Step
+                };
+            }
+            Some(b'Z') => match parse_point(&packet[1..]) {
+                Some((kind, address, length)) => {
+                    insert_point(gameboy, kind, address, length);
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            Some(b'z') => match parse_point(&packet[1..]) {
+                Some((kind, address, length)) => {
+                    remove_point(gameboy, kind, address, length);
+                    String::from("OK")
+                }
+                None => String::new(),
+            },
+            Some(b'k') => return Request::Kill,
+            Some(b'D') => return Request::Detach,
+            // A single thread, whatever the client selects
+            Some(b'H') => String::from("OK"),
+            Some(b'T') => String::from("OK"),
+            _ => self.query(packet),
+        };
+        Request::Reply(reply)
+    }
+
+    // Anything else gets an empty reply, which means "not supported"
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(request) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = request.split_once(',').and_then(|(offset, length)| {
+                Some((
+                    usize::from_str_radix(offset, 16).ok()?,
+                    usize::from_str_radix(length, 16).ok()?,
+                ))
+            }) else {
+                return String::from("E01");
+            };
+            let start = offset.min(TARGET_XML.len());
+            let end = start.saturating_add(length).min(TARGET_XML.len());
+            let more = if end < TARGET_XML.len() { 'm' } else { 'l' };
+            return format!("{}{}", more, &TARGET_XML[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                String::from("OK")
+            }
+            "qAttached" => String::from("1"),
+            "qC" => String::from("QC1"),
+            "qfThreadInfo" => String::from("m1"),
+            "qsThreadInfo" => String::from("l"),
+            "qSymbol::" => String::from("OK"),
+            _ => String::new(),
+        }
+    }
+
+    // Runs until a breakpoint, a watchpoint or ^C, or for one instruction. Returns the stop reply
+    fn resume(&mut self, gameboy: &mut GameBoy, step: bool) -> io::Result<String> {
+        let mut steps: u32 = 0;
+        loop {
+            let frame = gameboy.frame_count;
+            gameboy.step()?;
+            if gameboy.frame_count != frame {
+                // Nobody is listening while debugging
+                gameboy.mmu.apu.take_samples();
+            }
+            if let Some(stop) = gameboy.take_stop() {
+                return Ok(match stop {
+                    Stop::Watchpoint {
+                        address, access, ..
+                    } if !access.execute => {
+                        let kind = if access.write { "watch" } else { "rwatch" };
+                        format!("T{:02x}{}:{:x};", SIGTRAP, kind, address)
+                    }
+                    _ => stop_reply(SIGTRAP),
+                });
+            }
+            if step {
+                return Ok(stop_reply(SIGTRAP));
+            }
+
+            steps += 1;
+            if steps.is_multiple_of(INTERRUPT_POLL) && self.interrupted()? {
+                return Ok(stop_reply(SIGINT));
+            }
+        }
+    }
+
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+        match result {
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn read_register(gameboy: &GameBoy, register: usize) -> u16 {
+    let cpu = &gameboy.cpu;
+    match register {
+        0 => cpu.get_af(),
+        1 => cpu.get_bc(),
+        2 => cpu.get_de(),
+        3 => cpu.get_hl(),
+        4 => cpu.registers.sp,
+        _ => cpu.registers.pc,
+    }
+}
+
+fn write_register(gameboy: &mut GameBoy, register: usize, value: u16) {
+    let cpu = &mut gameboy.cpu;
+    match register {
+        // The low nibble of F doesn't exist
+        0 => cpu.set_af(value & 0xFFF0),
+        1 => cpu.set_bc(value),
+        2 => cpu.set_de(value),
+        3 => cpu.set_hl(value),
+        4 => cpu.registers.sp = value,
+        _ => cpu.registers.pc = value,
+    }
+}
+
+// Z/z packets: "kind,address,length". Kinds 0 and 1 are breakpoints, 2-4 watchpoints
+fn parse_point(text: &str) -> Option<(u8, u16, u16)> {
+    let mut fields = text.split(',');
+    let kind = fields.next()?.parse().ok().filter(|kind| *kind <= 4)?;
+    let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let length = u16::from_str_radix(fields.next()?.split(';').next()?, 16).ok()?;
+    Some((kind, address, length.max(1)))
+}
+
+fn watchpoint(kind: u8, address: u16, length: u16) -> Watchpoint {
+    let access = match kind {
+        2 => Access::WRITE,
+        3 => Access::READ,
+        _ => Access {
+            read: true,
+            write: true,
+            execute: false,
+        },
+    };
+    Watchpoint::new(address..=address.saturating_add(length - 1), access)
+}
+
+fn insert_point(gameboy: &mut GameBoy, kind: u8, address: u16, length: u16) {
+    if kind <= 1 {
+        let breakpoints = &mut gameboy.cpu.breakpoints;
+        if !breakpoints.iter().any(|b| b.address == address) {
+            breakpoints.push(Breakpoint::new(address, None));
+        }
+    } else {
+        gameboy
+            .mmu
+            .watchpoints
+            .push(watchpoint(kind, address, length));
+    }
+}
+
+fn remove_point(gameboy: &mut GameBoy, kind: u8, address: u16, length: u16) {
+    if kind <= 1 {
+        gameboy.cpu.breakpoints.retain(|b| b.address != address);
+    } else {
+        let removed = watchpoint(kind, address, length);
+        gameboy.mmu.watchpoints.retain(|w| *w != removed);
+    }
+}
+
+// "address,length" in hex
+fn parse_range(text: &str) -> Option<(u16, usize)> {
+    let (address, length) = text.split_once(',')?;
+    Some((
+        u16::from_str_radix(address, 16).ok()?,
+        usize::from_str_radix(length, 16).ok()?,
+    ))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b))
+}
+
+// '}' escapes the next byte, XORed with 0x20
+fn unescape(data: &[u8]) -> String {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        if byte == b'}' {
+            if let Some(&next) = bytes.next() {
+                out.push(next ^ 0x20);
+            }
+        } else {
+            out.push(byte);
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn hex_word(value: u16) -> String {
+    let [low, high] = value.to_le_bytes();
+    format!("{:02x}{:02x}", low, high)
+}
+
+// Pairs of hex digits. Stops at the first pair that isn't one
+fn from_hex(text: &str) -> Vec<u8> {
+    text.as_bytes()
+        .chunks_exact(2)
+        .map_while(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    #[test]
+    fn checksums_wrap_around() {
+        assert_eq!(checksum_of(b""), 0);
+        assert_eq!(checksum_of(b"OK"), 0x9A);
+        assert_eq!(checksum_of(b"qSupported:multiprocess+"), 0xC6); // Well past 256
+    }
+
+    #[test]
+    fn unescapes_binary_data() {
+        assert_eq!(unescape(b"m0,2"), "m0,2");
+        assert_eq!(unescape(b"X}\x03}]}\x04"), "X#}$");
+        assert_eq!(unescape(b"ab}"), "ab"); // Escape cut off
+    }
+
+    #[test]
+    fn parses_points() {
+        assert_eq!(parse_point("0,150,1"), Some((0, 0x0150, 1)));
+        assert_eq!(parse_point("2,c000,4"), Some((2, 0xC000, 4)));
+        assert_eq!(parse_point("1,4000,0;X1,ff"), Some((1, 0x4000, 1)));
+        assert_eq!(parse_point("5,150,1"), None);
+        assert_eq!(parse_point("0,10000,1"), None);
+        assert_eq!(parse_point("0,150"), None);
+    }
+
+    #[test]
+    fn watchpoints_cover_the_length() {
+        let watchpoint = watchpoint(4, 0xFFFE, 4);
+        assert_eq!(watchpoint.range, 0xFFFE..=0xFFFF);
+        assert!(watchpoint.access.read && watchpoint.access.write);
+
+        let mut gameboy = GameBoy::new(Model::DMG);
+        insert_point(&mut gameboy, 0, 0x0150, 1);
+        insert_point(&mut gameboy, 1, 0x0150, 1);
+        insert_point(&mut gameboy, 2, 0xC000, 2);
+        assert_eq!(gameboy.cpu.breakpoints.len(), 1);
+        assert_eq!(gameboy.mmu.watchpoints[0].range, 0xC000..=0xC001);
+        assert_eq!(gameboy.mmu.watchpoints[0].access, Access::WRITE);
+
+        remove_point(&mut gameboy, 3, 0xC000, 2); // Not the same kind
+        assert_eq!(gameboy.mmu.watchpoints.len(), 1);
+        remove_point(&mut gameboy, 2, 0xC000, 2);
+        remove_point(&mut gameboy, 0, 0x0150, 1);
+        assert!(gameboy.mmu.watchpoints.is_empty() && gameboy.cpu.breakpoints.is_empty());
+    }
+
+    #[test]
+    fn registers_are_little_endian_words() {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        write_register(&mut gameboy, 0, 0x12FF);
+        write_register(&mut gameboy, 5, 0x0150);
+        assert_eq!(read_register(&gameboy, 0), 0x12F0);
+        assert_eq!(hex_word(read_register(&gameboy, 5)), "5001");
+        assert_eq!(from_hex("5001zz"), [0x50, 0x01]);
+        assert_eq!(parse_range("c000,10"), Some((0xC000, 0x10)));
+    }
+}
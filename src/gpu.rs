@@ -1,9 +1,9 @@
-use crate::{
-    cpu::Registers,
-    mmu::{self, MMU},
-};
+use std::io;
 
-enum GPUControlRegisters {
+use crate::mmu::MMU;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub enum GPUControlRegisters {
     LCDC = 0xFF40,
     /*
     bit 7: LCD Display Enable (0=Off, 1=On) 1 to 0 only during VBlank
@@ -83,15 +83,13 @@ enum GPUControlRegisters {
     */
 }
 
-const SCANLINES_PER_FRAME: u16 = 154;
-const DOTS_PER_SCANLINE: u16 = 456;
 const DOT: u16 = 4; // 4 dots per M-cycle
 
+#[allow(non_camel_case_types)] // Named like the other hardware constants
 enum TileMap {
     TILE_MAP_1 = 0x9800, // Tile Map 1 (32x32 tiles) 0x9800-0x9BFF
     TILE_MAP_2 = 0x9C00, // Tile Map 2 (32x32 tiles) 0x9C00-0x9FFF
 }
-const TILE_MAP_LENTH: u16 = 1024; // 1024 bytes
 
 const OAM: usize = 0xFE00; // Object (Sprites) 0xFE00-0xFE9F
 const OAM_END: usize = 0xFE9F; // 4 x 40 bytes
@@ -99,6 +97,13 @@ const OAM_END: usize = 0xFE9F; // 4 x 40 bytes
 const WIDTH: usize = 160;
 const HEIGHT: usize = 144;
 
+/// Color index (0-3) of pixel `x` (0 is the leftmost) in a row of a 2bpp tile, from the row's
+/// two bytes: the low bits of every pixel first, then the high bits.
+pub fn tile_color(low: u8, high: u8, x: u8) -> u8 {
+    let bit = 7 - x;
+    ((high >> bit) & 1) << 1 | ((low >> bit) & 1)
+}
+
 pub struct OamObject {
     y: u8, // byte 0: Y position + 16
     x: u8, // byte 1: X position + 8
@@ -139,14 +144,10 @@ impl OamObject {
     }
 }
 
-struct Tile {
-    pixels: [u8; 8 * 8],
-}
-
 struct Pixel {
-    Color: u8,
-    Palette: Option<bool>,
-    Bg_priority: bool,
+    color: u8,
+    palette: Option<bool>,
+    bg_priority: bool,
 }
 
 pub struct Screen {
@@ -158,6 +159,12 @@ pub struct Screen {
     fifo_bg: Vec<Pixel>,
 }
 
+impl Default for Screen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Screen {
     pub fn new() -> Self {
         Screen {
@@ -185,7 +192,7 @@ impl Screen {
                         break;
                     }
                     if mmu.read_byte(i) - 16 == ly {
-                        self.obj_list.push(OamObject::new(i, &mmu));
+                        self.obj_list.push(OamObject::new(i, mmu));
                     }
                 }
 
@@ -199,7 +206,7 @@ impl Screen {
                 let dots = 172;
 
                 for x_coord in 0..WIDTH {
-                    let pixel_obj = self.get_pixel_obj(x_coord as u8, ly, mmu);
+                    let _pixel_obj = self.get_pixel_obj(x_coord as u8, ly, mmu);
                 }
 
                 dots
@@ -229,14 +236,11 @@ impl Screen {
             .filter(|obj| (obj.x..obj.x + 8).contains(&x))
             .min_by_key(|obj| obj.x);
 
-        match obj_in_range {
-            None => None,
-            Some(obj) => Some(Pixel {
-                Color: self.get_obj_color(obj, x, y, mmu),
-                Palette: self.get_obj_palette(obj),
-                Bg_priority: self.get_obj_priority(obj),
-            }),
-        }
+        obj_in_range.map(|obj| Pixel {
+            color: self.get_obj_color(obj, x, y, mmu),
+            palette: self.get_obj_palette(obj),
+            bg_priority: self.get_obj_priority(obj),
+        })
     }
 
     fn get_obj_color(&self, obj: &OamObject, x: u8, y: u8, mmu: &MMU) -> u8 {
@@ -263,8 +267,7 @@ impl Screen {
         let lower = mmu.read_byte(address);
         let higher: u8 = mmu.read_byte(address + 1);
 
-        let bit = 7 - x_rel;
-        ((higher >> bit) & 1) << 1 | ((lower >> bit) & 1)
+        tile_color(lower, higher, x_rel)
     }
 
     fn get_obj_palette(&self, obj: &OamObject) -> Option<bool> {
@@ -276,10 +279,76 @@ impl Screen {
     }
 
     fn get_obj_priority(&self, obj: &OamObject) -> bool {
-        if obj.flags & 0x80 != 0 {
-            true
+        obj.flags & 0x80 != 0
+    }
+}
+
+impl Pixel {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.color);
+        // 0 = no palette, 1 = OBP0, 2 = OBP1
+        w.u8(self.palette.map_or(0, |palette| palette as u8 + 1));
+        w.bool(self.bg_priority);
+    }
+
+    fn load_state(r: &mut StateReader) -> io::Result<Self> {
+        Ok(Pixel {
+            color: r.u8()? & 0x03,
+            palette: match r.u8()? {
+                0 => None,
+                palette => Some(palette == 2),
+            },
+            bg_priority: r.bool()?,
+        })
+    }
+}
+
+impl SaveState for Screen {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.pixels);
+        w.u8(self.ppu_mode);
+        w.u8(self.obj_list.len() as u8);
+        for obj in &self.obj_list {
+            w.bytes(&[obj.y, obj.x, obj.tile_index, obj.flags]);
+        }
+        w.bool(matches!(self.tile_map, TileMap::TILE_MAP_2));
+        for fifo in [&self.fifo_obj, &self.fifo_bg] {
+            w.u8(fifo.len() as u8);
+            for pixel in fifo {
+                pixel.save_state(w);
+            }
+        }
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.pixels)?;
+        self.ppu_mode = r.u8()?;
+        if self.ppu_mode > 3 {
+            return Err(crate::savestate::invalid("invalid PPU mode in save state"));
+        }
+        let objects = r.u8()?;
+        self.obj_list.clear();
+        for _ in 0..objects {
+            let [y, x, tile_index, flags]: [u8; 4] = r.bytes(4)?.try_into().unwrap();
+            self.obj_list.push(OamObject {
+                y,
+                x,
+                tile_index,
+                flags,
+            });
+        }
+        self.tile_map = if r.bool()? {
+            TileMap::TILE_MAP_2
         } else {
-            false
+            TileMap::TILE_MAP_1
+        };
+        for fifo in [&mut self.fifo_obj, &mut self.fifo_bg] {
+            let len = r.u8()?;
+            fifo.clear();
+            for _ in 0..len {
+                fifo.push(Pixel::load_state(r)?);
+            }
         }
+        Ok(())
     }
 }
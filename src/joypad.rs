@@ -0,0 +1,170 @@
+use std::io;
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const P1: u16 = 0xFF00;
+/*
+P1/JOYP: Joypad
+bit 7-6: not used, read as 1
+bit 5: Select buttons (0=Selected)
+bit 4: Select d-pad (0=Selected)
+bit 3: Start / Down (0=Pressed). READ-ONLY
+bit 2: Select / Up (0=Pressed). READ-ONLY
+bit 1: B / Left (0=Pressed). READ-ONLY
+bit 0: A / Right (0=Pressed). READ-ONLY
+*/
+
+const SELECT_BUTTONS: u8 = 0b0010_0000;
+const SELECT_DPAD: u8 = 0b0001_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    // D-pad, low nibble of `Joypad::pressed`
+    Right = 0,
+    Left = 1,
+    Up = 2,
+    Down = 3,
+    // Buttons, high nibble of `Joypad::pressed`
+    A = 4,
+    B = 5,
+    Select = 6,
+    Start = 7,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+}
+
+pub struct Joypad {
+    pub select: u8,  // Bits 4-5 of P1 as last written
+    pub pressed: u8, // One bit per `Button`, 1 = pressed
+}
+
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad {
+            select: SELECT_BUTTONS | SELECT_DPAD, // Nothing selected
+            pressed: 0,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        0b1100_0000 | self.select | (!self.lines() & 0x0F)
+    }
+
+    /// Writes the select bits. Returns true if a line went from high to low (joypad interrupt).
+    pub fn write(&mut self, value: u8) -> bool {
+        let before = self.lines();
+        self.select = value & (SELECT_BUTTONS | SELECT_DPAD);
+        self.lines() & !before != 0
+    }
+
+    /// Returns true if the change pulls a selected line low (joypad interrupt).
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let before = self.lines();
+        if pressed {
+            self.pressed |= 1 << button as u8;
+        } else {
+            self.pressed &= !(1 << button as u8);
+        }
+        self.lines() & !before != 0
+    }
+
+    pub fn is_pressed(&self, button: Button) -> bool {
+        self.pressed & (1 << button as u8) != 0
+    }
+
+    // Active-high state of P1 bits 0-3: pressed keys of every selected group
+    fn lines(&self) -> u8 {
+        let mut lines = 0;
+        if self.select & SELECT_DPAD == 0 {
+            lines |= self.pressed & 0x0F;
+        }
+        if self.select & SELECT_BUTTONS == 0 {
+            lines |= self.pressed >> 4;
+        }
+        lines
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.select);
+        w.u8(self.pressed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.select = r.u8()?;
+        self.pressed = r.u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn joypad(pressed: &[Button]) -> Joypad {
+        let mut joypad = Joypad::new();
+        for &button in pressed {
+            joypad.set_button(button, true);
+        }
+        joypad
+    }
+
+    #[test]
+    fn select_bits_pick_the_group_read() {
+        let mut joypad = joypad(&[Button::Right, Button::Start]);
+        assert_eq!(joypad.read(), 0xFF); // Nothing selected
+        joypad.write(0x20);
+        assert_eq!(joypad.read(), 0xEE); // D-pad: Right
+        joypad.write(0x10);
+        assert_eq!(joypad.read(), 0xD7); // Buttons: Start
+        joypad.write(0x00);
+        assert_eq!(joypad.read(), 0xC6); // Both groups share the lines
+    }
+
+    #[test]
+    fn only_the_select_bits_are_writable() {
+        let mut joypad = Joypad::new();
+        joypad.write(0x0F);
+        assert_eq!(joypad.read(), 0xCF);
+        joypad.write(0xF0);
+        assert_eq!(joypad.read(), 0xFF);
+    }
+
+    #[test]
+    fn presses_interrupt_only_on_selected_lines() {
+        let mut joypad = Joypad::new();
+        assert!(!joypad.set_button(Button::A, true)); // Not selected
+        joypad.write(0x20);
+        assert!(joypad.set_button(Button::Left, true));
+        assert!(!joypad.set_button(Button::Left, true)); // Already low
+        assert!(!joypad.set_button(Button::Left, false)); // Released: low to high
+        assert!(joypad.is_pressed(Button::A) && !joypad.is_pressed(Button::Left));
+    }
+
+    #[test]
+    fn selecting_a_group_with_a_key_held_interrupts() {
+        let mut joypad = joypad(&[Button::B]);
+        assert!(!joypad.write(0x20)); // D-pad: nothing held
+        assert!(joypad.write(0x10)); // Buttons: B pulls its line low
+        assert!(!joypad.write(0x00)); // Still low
+        assert!(!joypad.write(0x30));
+    }
+}
@@ -0,0 +1,28 @@
+#![allow(non_snake_case)] // Crate name
+
+pub mod apu;
+pub mod breakpoint;
+pub mod callstack;
+pub mod checksum;
+pub mod coverage;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod gameboy;
+pub mod gbs;
+pub mod gdb;
+pub mod gpu;
+pub mod joypad;
+pub mod mmu;
+pub mod model;
+pub mod movie;
+mod op_codes;
+pub mod png;
+pub mod printer;
+pub mod profiler;
+pub mod rewind;
+pub mod savestate;
+pub mod serial;
+pub mod symbols;
+pub mod tiles;
+pub mod wav;
@@ -1,49 +1,351 @@
+#![allow(non_snake_case)] // Crate name
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 use std::time::Duration;
 
-use mmu::MMU;
+use Rustboy::apu::to_i16;
+use Rustboy::coverage::Coverage;
+use Rustboy::debugger::Debugger;
+use Rustboy::gameboy::{GameBoy, TestResult, FRAME_TIME};
+use Rustboy::gbs::{self, Gbs, GbsPlayer};
+use Rustboy::gdb;
+use Rustboy::movie::Movie;
+use Rustboy::printer::Printer;
+use Rustboy::rewind::Rewind;
+use Rustboy::serial::{link, SerialBuffer, SerialSink};
+use Rustboy::tiles;
+use Rustboy::wav::AudioRecorder;
 
-use crate::cpu::CPU;
+mod cli;
 
-mod cpu;
-pub mod gpu;
-pub mod mmu;
-mod op_codes;
-// T-cycles = Clock cycles. 1 M-cycle = 4 T-cycles
-const CYCLES_PER_FRAME: u32 = 70224 / 4; // M-cycles.
-const FRAME_TIME: Duration = Duration::from_micros((1_000_000.0 / 59.7) as u64);
-const ROM_PATH: &str = r"rom\test\instr_timing\instr_timing.gb";
+const PROFILE_HOT_SPOTS: usize = 50; // Addresses listed by --profile
 
-fn main() {
-    let mut mmu = MMU::new();
-    let mut cpu = CPU::new();
-    let mut gpu = gpu::Screen::new();
-    let mut gpu_dots = 0;
-    let mut last_frame_time = std::time::Instant::now();
+fn main() -> ExitCode {
+    let args = match cli::parse(std::env::args().skip(1)) {
+        Ok(cli::Command::Run(args)) => args,
+        Ok(cli::Command::Help) => {
+            println!("{}", cli::USAGE);
+            return ExitCode::SUCCESS;
+        }
+        Err(err) => {
+            eprintln!("error: {}\n\n{}", err, cli::USAGE);
+            return ExitCode::from(2);
+        }
+    };
+
+    match run(&args) {
+        Ok(Some(TestResult::Failed)) => ExitCode::from(1),
+        Ok(_) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("error: {}", err);
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn run(args: &cli::Args) -> io::Result<Option<TestResult>> {
+    if gbs::is_gbs(&args.rom) {
+        return export_gbs(args).map(|()| None);
+    }
+
+    let mut gameboy = GameBoy::new(args.model);
+    gameboy.set_serial_sink(Box::<StdoutSink>::default());
 
     // Load the ROM into memory
-    mmu.read_rom(ROM_PATH);
+    gameboy.load_rom(&args.rom).map_err(with_path(&args.rom))?;
+
+    // A missing .sym next to the ROM is fine, one given with --symbols isn't
+    let symbols = match &args.symbols {
+        Some(path) => Some(path.clone()),
+        None => Some(args.rom.with_extension("sym")).filter(|path| path.exists()),
+    };
+    if let Some(path) = &symbols {
+        gameboy.load_symbols(path).map_err(with_path(path))?;
+    }
+
+    if let Some(boot_rom) = &args.boot_rom {
+        gameboy
+            .load_boot_rom(boot_rom)
+            .map_err(with_path(boot_rom))?;
+    }
+
+    if let Some(path) = &args.load_state {
+        gameboy.load_state_file(path).map_err(with_path(path))?;
+    }
+
+    if let Some(path) = &args.play_movie {
+        let movie = Movie::load(path).map_err(with_path(path))?;
+        gameboy
+            .play_movie(movie)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+    if args.record_movie.is_some() {
+        gameboy
+            .start_recording()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    }
+
+    match &args.link {
+        Some(cli::LinkMode::Listen(address)) => {
+            println!("Waiting for the link cable on {}", address);
+            gameboy.set_link(link::listen(address).map_err(with_path(Path::new(address)))?);
+        }
+        Some(cli::LinkMode::Connect(address)) => {
+            gameboy.set_link(link::connect(address).map_err(with_path(Path::new(address)))?)
+        }
+        Some(cli::LinkMode::Printer(dir)) => gameboy.set_link(Box::new(Printer::new(dir))),
+        None => {}
+    }
+
+    let save_path = save_path(args).filter(|_| gameboy.mmu.has_battery());
+    if let Some(path) = &save_path {
+        if let Ok(data) = fs::read(path) {
+            gameboy.mmu.load_cartridge_ram(&data);
+        }
+    }
+
+    if args.profile.is_some() || args.profile_folded.is_some() {
+        gameboy.cpu.profiler = Some(Box::default());
+    }
+
+    if let Some(path) = &args.cdl {
+        let rom_length = gameboy.mmu.rom.len();
+        gameboy.mmu.coverage = Some(if path.exists() {
+            Coverage::load(path, rom_length).map_err(with_path(path))?
+        } else {
+            Coverage::new(rom_length)
+        });
+    }
+
+    if let Some(trace) = &args.trace {
+        gameboy.set_trace(Box::new(BufWriter::new(File::create(trace)?)));
+    }
 
-    // Start the fetch-decode-execute cycle
-    loop {
-        //print!("cycles: {}\n", cpu.registers.A);
-        let mut cycles_elapsed: u32 = 0;
+    gameboy.mmu.apu.set_sample_rate(args.audio_rate);
+    let mut audio_out = match &args.audio_out {
+        Some(path) => Some(BufWriter::new(File::create(path).map_err(with_path(path))?)),
+        None => None,
+    };
+    let mut audio_recorder = match &args.record_audio {
+        Some(path) => Some(
+            AudioRecorder::create(path, &mut gameboy.mmu.apu, args.record_channels)
+                .map_err(with_path(path))?,
+        ),
+        None => None,
+    };
+
+    // With an audio stream the consumer sets the pace: writes block while its buffer is full
+    let frame_time = if args.headless || args.speed == 0.0 || audio_out.is_some() {
+        None
+    } else {
+        Some(Duration::from_secs_f64(
+            FRAME_TIME.as_secs_f64() / args.speed,
+        ))
+    };
+    let mut last_frame_time = std::time::Instant::now();
+
+    let mut tiles_saved = false;
+
+    // A crash (illegal opcode, or a bug of ours) shows what the CPU was doing before unwinding
+    let emulation = panic::catch_unwind(AssertUnwindSafe(|| -> io::Result<()> {
+        // Debuggers drive emulation themselves, one command at a time
+        let debugging = args.debug || args.gdb.is_some();
+        if args.debug {
+            gameboy.set_rewind(Some(Rewind::default()));
+            Debugger::new().run(&mut gameboy, io::stdin().lock(), &mut io::stdout())?;
+        }
+        if let Some(port) = args.gdb {
+            let address = format!("127.0.0.1:{}", port);
+            println!("Waiting for gdb on {}", address);
+            gdb::serve(&mut gameboy, &address).map_err(with_path(Path::new(&address)))?;
+        }
+
+        // Start the fetch-decode-execute cycle
+        while !debugging && args.frames.is_none_or(|n| gameboy.frame_count < n) {
+            gameboy.run_frame()?;
+
+            if let (Some(path), Some(frame)) = (&args.tiles, args.tiles_frame) {
+                if gameboy.frame_count == frame {
+                    tiles::save(&gameboy.mmu, args.tiles_palette, path).map_err(with_path(path))?;
+                    tiles_saved = true;
+                }
+            }
+
+            let samples = gameboy.mmu.apu.take_samples();
+            if let Some(out) = audio_out.as_mut() {
+                let bytes: Vec<u8> = samples
+                    .iter()
+                    .flat_map(|s| to_i16(*s).to_le_bytes())
+                    .collect();
+                out.write_all(&bytes)?;
+                out.flush()?;
+            }
+            if let Some(recorder) = audio_recorder.as_mut() {
+                recorder.record(&samples, &mut gameboy.mmu.apu)?;
+            }
 
-        while cycles_elapsed < CYCLES_PER_FRAME {
-            let cycles = cpu.step(&mut mmu) as u32;
-            cycles_elapsed += cycles;
+            // Test ROMs loop forever after reporting, so scripts stop as soon as there is a result
+            if args.headless && gameboy.test_result().is_some() {
+                break;
+            }
+            if args.headless && args.frames.is_none() && gameboy.movie_finished() {
+                break;
+            }
 
-            gpu_dots -= cycles * 4;
-            if gpu_dots <= 0 {
-                gpu_dots += gpu.step(&mut mmu) as u32;
+            // Sincronizar tiempo
+            if let Some(frame_time) = frame_time {
+                let elapsed_time = last_frame_time.elapsed();
+                if elapsed_time < frame_time {
+                    std::thread::sleep(frame_time - elapsed_time);
+                }
             }
+
+            last_frame_time = std::time::Instant::now();
+        }
+        Ok(())
+    }));
+    match emulation {
+        Ok(result) => result?,
+        Err(panic) => {
+            eprintln!("Game Boy call stack:");
+            let _ = gameboy.write_stack_trace(&mut io::stderr());
+            panic::resume_unwind(panic);
+        }
+    }
+
+    // Test ROMs that don't print through the serial port leave their text in cartridge RAM
+    if let Some((_, text)) = gameboy.memory_test_report() {
+        if gameboy.serial_output().is_empty() {
+            println!("{}", text.trim_end());
+        }
+    }
+
+    if let Some(recorder) = audio_recorder {
+        recorder.finish()?;
+    }
+
+    if let Some(profiler) = &gameboy.cpu.profiler {
+        if let Some(path) = &args.profile {
+            let mut out = BufWriter::new(File::create(path).map_err(with_path(path))?);
+            profiler.write_report(&gameboy.symbols, PROFILE_HOT_SPOTS, &mut out)?;
+            out.flush()?;
+        }
+        if let Some(path) = &args.profile_folded {
+            let mut out = BufWriter::new(File::create(path).map_err(with_path(path))?);
+            profiler.write_folded(&gameboy.symbols, &mut out)?;
+            out.flush()?;
+        }
+    }
+
+    // Also when emulation ended before --tiles-frame
+    if let (Some(path), false) = (&args.tiles, tiles_saved) {
+        tiles::save(&gameboy.mmu, args.tiles_palette, path).map_err(with_path(path))?;
+    }
+
+    if let (Some(path), Some(coverage)) = (&args.cdl, &gameboy.mmu.coverage) {
+        coverage.save(path).map_err(with_path(path))?;
+    }
+
+    if let Some(path) = &args.save_state {
+        gameboy.save_state_file(path).map_err(with_path(path))?;
+    }
+
+    if let Some(path) = &args.record_movie {
+        if let Some(movie) = gameboy.take_recording() {
+            movie.save(path).map_err(with_path(path))?;
+        }
+    }
+
+    if let Some(path) = &save_path {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
         }
+        fs::write(path, gameboy.mmu.cartridge_ram())?;
+    }
+
+    Ok(gameboy.test_result())
+}
+
+/// Shows serial output as it comes (test ROMs print their results there) and keeps it.
+#[derive(Default)]
+struct StdoutSink(SerialBuffer);
+
+impl SerialSink for StdoutSink {
+    fn transmit(&mut self, byte: u8) {
+        print!("{}", byte as char);
+        let _ = io::stdout().flush();
+        self.0.transmit(byte);
+    }
+
+    fn output(&self) -> &[u8] {
+        self.0.output()
+    }
+}
+
+const GBS_DEFAULT_FRAMES: u64 = 3600; // About a minute
 
-        // Sincronizar tiempo
-        let elapsed_time = last_frame_time.elapsed();
-        if elapsed_time < FRAME_TIME {
-            std::thread::sleep(FRAME_TIME - elapsed_time);
+/// Renders GBS songs to WAV: the one given by `--song`, or each of them to `<name>-NN.wav`, from
+/// the file's first song on.
+fn export_gbs(args: &cli::Args) -> io::Result<()> {
+    let invalid_input = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let Some(path) = &args.record_audio else {
+        return Err(invalid_input(String::from(
+            "GBS files need --record-audio to export to",
+        )));
+    };
+    let gbs = Gbs::load(&args.rom).map_err(with_path(&args.rom))?;
+    println!(
+        "{} - {} ({}), {} songs, first {}",
+        gbs.title, gbs.author, gbs.copyright, gbs.song_count, gbs.first_song
+    );
+
+    let songs: Vec<u8> = match args.song {
+        Some(song) => vec![song],
+        None => gbs.songs().collect(),
+    };
+    for song in songs {
+        let mut player = GbsPlayer::new(gbs.clone(), args.model, song).map_err(invalid_input)?;
+        let apu = &mut player.gameboy.mmu.apu;
+        apu.set_sample_rate(args.audio_rate);
+
+        let out = match args.song {
+            Some(_) => path.clone(),
+            None => {
+                let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                path.with_file_name(format!("{}-{:02}.wav", stem, song))
+            }
+        };
+        let mut recorder =
+            AudioRecorder::create(&out, apu, args.record_channels).map_err(with_path(&out))?;
+
+        let frames = args.frames.unwrap_or(GBS_DEFAULT_FRAMES);
+        while player.gameboy.frame_count < frames {
+            player.run_frame()?;
+            let apu = &mut player.gameboy.mmu.apu;
+            let samples = apu.take_samples();
+            recorder.record(&samples, apu)?;
         }
+        recorder.finish()?;
+        println!("Song {}: {}", song, out.display());
+    }
+    Ok(())
+}
+
+fn with_path(path: &Path) -> impl FnOnce(io::Error) -> io::Error + '_ {
+    move |e| io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+}
 
-        last_frame_time = std::time::Instant::now();
+/// Battery save file for the ROM: `<save-dir>/<rom name>.sav`, next to the ROM by default.
+/// Headless runs (scripts, test ROMs) only keep saves in a --save-dir.
+fn save_path(args: &cli::Args) -> Option<PathBuf> {
+    let path = args.rom.with_extension("sav");
+    match &args.save_dir {
+        Some(dir) => Some(dir.join(path.file_name().unwrap_or_default())),
+        None if args.headless => None,
+        None => Some(path),
     }
 }
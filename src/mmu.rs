@@ -1,4 +1,19 @@
-use std::{fs::File, io::Read};
+use std::{
+    cell::Cell,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use crate::apu::{self, Apu};
+use crate::breakpoint::{Access, Watchpoint};
+use crate::checksum::crc32;
+use crate::coverage::{self, Coverage};
+use crate::cpu::{ControlRegisters, InterruptCode};
+use crate::joypad::{self, Button, Joypad};
+use crate::model::Model;
+use crate::savestate::{SaveState, StateReader, StateWriter};
+use crate::serial::{self, Serial, SerialBuffer, SerialSink};
 
 const MEMORY_SIZE: usize = 65536;
 const ROM_BANK_0: usize = 0x0000; // ROM Bank 0 (32KB) HOME BANK
@@ -6,52 +21,379 @@ const ROM_BANK_1: usize = 0x4000; // ROM Bank 1 (32KB)
 const VRAM: usize = 0x8000; // VRAM (8KB). $8000-$97FF
 const VRAM_LENGTH: u16 = 8192;
 const CARTRIDGE_RAM: usize = 0xA000;
-const WORK_RAM: usize = 0xC000; // RAM Bank 0 (8KB)
-                                // Space not used
+// Work RAM 0xC000-0xDFFF, echo RAM up to 0xFDFF
 const OAM: usize = 0xFE00; // OAM (Sprites) (160 bytes) also tiles
 const OAM_LENGTH: u16 = 160;
 //Space not used
 const IO_REGISTERS: usize = 0xFF00; // IO Registros (80 bytes)
 const HIGH_RAM: usize = 0xFF80; // Memoria de alto rendimiento (128 bytes) //Acceso un ciclo mas rapido
 
+const BOOT_ROM_LENGTH: usize = 0x100; // DMG boot ROM, mapped over 0x0000-0x00FF
+const CGB_BOOT_ROM_LENGTH: usize = 0x900; // CGB boot ROM also covers 0x0200-0x08FF
+const BOOT_ROM_DISABLE: u16 = 0xFF50; // Writing a non-zero value unmaps the boot ROM
+const CARTRIDGE_TYPE: usize = 0x0147; // Cartridge header: mapper and extra hardware
+const CARTRIDGE_RAM_LENGTH: usize = 8192;
+const ROM_BANK_LENGTH: usize = 0x4000;
+const ROM_BANK_SELECT: u16 = 0x2000; // Writes to 0x2000-0x3FFF pick the bank mapped at 0x4000
+const VBK: u16 = 0xFF4F; // CGB VRAM bank mapped at 0x8000, bit 0
+
+// IO registers as left by the DMG boot ROM. Other models are patched in `init_io_registers`
+const POST_BOOT_IO: [(u16, u8); 38] = [
+    (0xFF00, 0xCF), // P1
+    (0xFF02, 0x7E), // SC
+    (0xFF07, 0xF8), // TAC
+    (0xFF0F, 0xE1), // IF
+    (0xFF10, 0x80), // NR10
+    (0xFF11, 0xBF), // NR11
+    (0xFF12, 0xF3), // NR12
+    (0xFF13, 0xFF), // NR13
+    (0xFF14, 0xBF), // NR14
+    (0xFF16, 0x3F), // NR21
+    (0xFF17, 0x00), // NR22
+    (0xFF18, 0xFF), // NR23
+    (0xFF19, 0xBF), // NR24
+    (0xFF1A, 0x7F), // NR30
+    (0xFF1B, 0xFF), // NR31
+    (0xFF1C, 0x9F), // NR32
+    (0xFF1D, 0xFF), // NR33
+    (0xFF1E, 0xBF), // NR34
+    (0xFF20, 0xFF), // NR41
+    (0xFF21, 0x00), // NR42
+    (0xFF22, 0x00), // NR43
+    (0xFF23, 0xBF), // NR44
+    (0xFF24, 0x77), // NR50
+    (0xFF25, 0xF3), // NR51
+    (0xFF26, 0xF1), // NR52
+    (0xFF40, 0x91), // LCDC
+    (0xFF41, 0x85), // STAT
+    (0xFF42, 0x00), // SCY
+    (0xFF43, 0x00), // SCX
+    (0xFF44, 0x00), // LY
+    (0xFF45, 0x00), // LYC
+    (0xFF46, 0xFF), // DMA
+    (0xFF47, 0xFC), // BGP
+    (0xFF48, 0xFF), // OBP0
+    (0xFF49, 0xFF), // OBP1
+    (0xFF4A, 0x00), // WY
+    (0xFF4B, 0x00), // WX
+    (0xFFFF, 0x00), // IE
+];
+
 pub struct MMU {
     pub memory: [u8; MEMORY_SIZE], // Memoria de la CPU
     pub oam_enable: bool,
     pub vram_enable: bool,
+    pub serial_sink: Box<dyn SerialSink>, // Gets the bytes sent through the serial port
+    pub serial: Serial,
+    pub boot_rom: Vec<u8>,
+    pub boot_rom_enabled: bool,
+    pub model: Model,
+    pub joypad: Joypad,
+    pub rom_crc32: u32, // CRC-32 of the whole ROM file
+    pub rom: Vec<u8>,   // Whole ROM image, banks beyond the first two are copied in on demand
+    pub rom_bank: usize,
+    // Only the GBS player's cartridge switches banks. No mapper is emulated, so other ROMs
+    // ignore writes to 0x0000-0x7FFF
+    pub rom_bank_switching: bool,
+    pub vram_bank: usize,
+    other_vram: Vec<u8>, // The VRAM bank that isn't mapped, CGB only
+    pub apu: Apu,
+    pub watchpoints: Vec<Watchpoint>,
+    pub coverage: Option<Coverage>, // Code/data log of the ROM, when on
+    watch_hit: Cell<Option<(u16, Access, u8)>>, // First watched access since the last take
+    bus_cycles: Cell<u32>,          // Accesses so far in the current instruction, one M-cycle each
+}
+
+impl Default for MMU {
+    fn default() -> Self {
+        Self::new(Model::default())
+    }
 }
 
 impl MMU {
-    pub fn new() -> Self {
-        MMU {
+    pub fn new(model: Model) -> Self {
+        let mut mmu = MMU {
             memory: [0; MEMORY_SIZE],
             oam_enable: true,
             vram_enable: true,
+            serial_sink: Box::new(SerialBuffer::default()),
+            serial: Serial::new(model.is_cgb()),
+            boot_rom: Vec::new(),
+            boot_rom_enabled: false,
+            model,
+            joypad: Joypad::new(),
+            rom_crc32: 0,
+            rom: Vec::new(),
+            rom_bank: 1,
+            rom_bank_switching: false,
+            vram_bank: 0,
+            other_vram: vec![0; VRAM_LENGTH as usize],
+            apu: Apu::new(model),
+            watchpoints: Vec::new(),
+            coverage: None,
+            watch_hit: Cell::new(None),
+            bus_cycles: Cell::new(0),
+        };
+        mmu.init_io_registers();
+        mmu
+    }
+
+    fn init_io_registers(&mut self) {
+        // Power the APU first so the other sound registers take their values
+        self.apu.write(apu::NR52, 0x80, 0);
+        for (address, value) in POST_BOOT_IO {
+            if Apu::handles(address) {
+                // Post-boot values are readbacks: the trigger bit must not retrigger the channel
+                let value = if matches!(address, 0xFF14 | 0xFF19 | 0xFF1E | 0xFF23) {
+                    value & 0x7F
+                } else {
+                    value
+                };
+                self.apu.write(address, value, 0);
+            } else if Serial::handles(address) {
+                self.serial.write(address, value);
+            } else if address == joypad::P1 {
+                self.joypad.write(value); // Both groups selected, as the boot ROM leaves them
+            } else {
+                self.memory[address as usize] = value;
+            }
+        }
+        self.memory[ControlRegisters::DIV as usize] = self.model.div_phase().0;
+
+        match self.model {
+            Model::DMG0 => self.memory[0xFF41] = 0x81,
+            Model::SGB | Model::SGB2 => self.memory[0xFF26] = 0xF0,
+            Model::CGB | Model::AGB => {
+                self.serial.write(serial::SC, 0x7F);
+                self.memory[0xFF46] = 0x00;
+            }
+            Model::DMG | Model::MGB => {}
         }
     }
 
+    /// The CPU starts an instruction: memory accesses count M-cycles from here.
+    pub fn start_instruction(&self) {
+        self.bus_cycles.set(0);
+    }
+
+    // M-cycle of the current instruction an access happens in
+    #[inline]
+    fn bus_cycle(&self) -> u32 {
+        let cycle = self.bus_cycles.get();
+        self.bus_cycles.set(cycle + 1);
+        cycle
+    }
+
+    // Every memory access goes through here: without the hint the extra layer is measurable
+    #[inline]
     pub fn read_byte(&self, address: u16) -> u8 {
+        let value = self.load(address, self.bus_cycle());
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::READ, value);
+        }
+        if self.coverage.is_some() {
+            self.cover(address, coverage::DATA);
+        }
+        value
+    }
+
+    /// Instruction fetch: `read_byte` without the read watchpoints, which are for data.
+    #[inline]
+    pub fn read_code(&self, address: u16) -> u8 {
+        if self.coverage.is_some() {
+            self.cover(address, coverage::CODE);
+        }
+        self.load(address, self.bus_cycle())
+    }
+
+    /*
+    peek and poke are for debuggers, cheats and other tools looking at the machine from outside:
+    no timing, no watchpoints, no OAM/VRAM locking and none of what a CPU write sets off (DIV
+    reset, bank switching, boot ROM unmapping, joypad interrupt). Registers kept by the APU,
+    serial port and joypad are still written through them, as that is where they are stored.
+    */
+
+    /// What the CPU would read at `address`, without side effects.
+    pub fn peek(&self, address: u16) -> u8 {
+        self.load(address, 0)
+    }
+
+    // A read `cycle` M-cycles into the current instruction
+    #[inline]
+    fn load(&self, address: u16, cycle: u32) -> u8 {
+        if self.boot_rom_enabled && self.is_boot_rom_address(address) {
+            return self.boot_rom[address as usize];
+        }
+        if address == joypad::P1 {
+            return self.joypad.read();
+        }
+        if Apu::handles(address) {
+            return self.apu.read(address, cycle);
+        }
+        if Serial::handles(address) {
+            return self.serial.read(address);
+        }
+        if address == VBK && self.model.is_cgb() {
+            return 0xFE | self.vram_bank as u8;
+        }
         self.memory[address as usize]
     }
+
+    /// Stores `value` at `address` as is. ROM is patched in place, so the patch survives bank
+    /// switches.
+    pub fn poke(&mut self, address: u16, value: u8) {
+        if self.boot_rom_enabled && self.is_boot_rom_address(address) {
+            self.boot_rom[address as usize] = value;
+        } else if address == joypad::P1 {
+            self.joypad.write(value);
+        } else if Apu::handles(address) {
+            self.apu.write(address, value, 0);
+        } else if Serial::handles(address) {
+            self.serial.write(address, value);
+        } else if address == VBK && self.model.is_cgb() {
+            self.select_vram_bank(value as usize & 1); // VBK is kept as the mapped bank
+        } else {
+            if address < VRAM as u16 {
+                let offset = self.mapped_rom_offset(address);
+                if let Some(byte) = self.rom.get_mut(offset) {
+                    *byte = value;
+                }
+            }
+            self.memory[address as usize] = value;
+        }
+    }
+
+    /// Byte at `address` in `bank`, mapped or not. None if there is no such bank.
+    pub fn peek_banked(&self, bank: u16, address: u16) -> Option<u8> {
+        if bank == self.mapped_bank(address) {
+            Some(self.peek(address))
+        } else if let Some(offset) = self.other_vram_offset(bank, address) {
+            Some(self.other_vram[offset])
+        } else {
+            self.rom_offset(bank, address)
+                .and_then(|offset| self.rom.get(offset).copied())
+        }
+    }
+
+    /// `poke` into any bank. Returns false if there is no such bank.
+    pub fn poke_banked(&mut self, bank: u16, address: u16, value: u8) -> bool {
+        if bank == self.mapped_bank(address) {
+            self.poke(address, value);
+            return true;
+        }
+        if let Some(offset) = self.other_vram_offset(bank, address) {
+            self.other_vram[offset] = value;
+            return true;
+        }
+        match self
+            .rom_offset(bank, address)
+            .and_then(|offset| self.rom.get_mut(offset))
+        {
+            Some(byte) => {
+                *byte = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Bank mapped at `address` right now, numbered as RGBDS does: the ROM bank for
+    /// 0x4000-0x7FFF, the VRAM bank for 0x8000-0x9FFF, 1 for WRAM 0xD000-0xDFFF and 0 for
+    /// everything else.
+    pub fn mapped_bank(&self, address: u16) -> u16 {
+        match address as usize {
+            ROM_BANK_1..VRAM => self.rom_bank as u16,
+            VRAM..CARTRIDGE_RAM => self.vram_bank as u16,
+            0xD000..=0xDFFF => 1, // No WRAM banking yet
+            _ => 0,
+        }
+    }
+
+    // Where a ROM address (below 0x8000) is in the ROM image, with the banks mapped now
+    fn mapped_rom_offset(&self, address: u16) -> usize {
+        match address as usize {
+            offset @ ROM_BANK_0..ROM_BANK_1 => offset,
+            offset => self.rom_bank * ROM_BANK_LENGTH + offset - ROM_BANK_1,
+        }
+    }
+
+    // Where a switchable ROM bank address is in the ROM image. Other memory has a single bank
+    fn rom_offset(&self, bank: u16, address: u16) -> Option<usize> {
+        match address as usize {
+            offset @ ROM_BANK_1..VRAM => {
+                Some(bank as usize * ROM_BANK_LENGTH + offset - ROM_BANK_1)
+            }
+            _ => None,
+        }
+    }
+
+    // Where a VRAM address is in `other_vram`, if `bank` is the one that isn't mapped
+    fn other_vram_offset(&self, bank: u16, address: u16) -> Option<usize> {
+        let mapped = (VRAM..CARTRIDGE_RAM).contains(&(address as usize));
+        (mapped && self.model.is_cgb() && bank as usize == self.vram_bank ^ 1)
+            .then(|| address as usize - VRAM)
+    }
+
+    fn is_boot_rom_address(&self, address: u16) -> bool {
+        let address = address as usize;
+        address < BOOT_ROM_LENGTH
+            || (self.boot_rom.len() == CGB_BOOT_ROM_LENGTH
+                && (0x200..CGB_BOOT_ROM_LENGTH).contains(&address))
+    }
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        // Rom test
-        if address == 0xFF01 && self.memory[0xFF02] == 0x81 {
-            print!("{}", value as char);
-            self.memory[0xFF02] = 0x00;
+        let cycle = self.bus_cycle();
+        if !self.watchpoints.is_empty() {
+            self.watch(address, Access::WRITE, value);
+        }
+        if self.coverage.is_some() {
+            self.cover(address, coverage::WRITTEN);
+        }
+
+        // Boot ROM is unmapped for good once the boot sequence writes here
+        if address == BOOT_ROM_DISABLE && value != 0 {
+            self.boot_rom_enabled = false;
+        }
+
+        if address == joypad::P1 {
+            if self.joypad.write(value) {
+                self.request_interrupt(InterruptCode::Joypad);
+            }
+            return;
+        }
+
+        if Apu::handles(address) {
+            self.apu.write(address, value, cycle);
+            return;
+        }
+
+        if Serial::handles(address) {
+            self.serial.write(address, value);
+            return;
+        }
+
+        if address == VBK && self.model.is_cgb() {
+            self.select_vram_bank(value as usize & 1);
+            return;
         }
 
         // Divider register
         if address == 0xFF04 {
+            let old = self.memory[address as usize];
             self.memory[address as usize] = 0;
+            self.apu.div_changed(old, 0);
             return;
         }
 
-        // ROM BANK 0
+        // ROM is read-only, but the GBS player's cartridge switches banks when written
         if address < VRAM as u16 {
+            if self.rom_bank_switching && (ROM_BANK_SELECT..ROM_BANK_1 as u16).contains(&address) {
+                self.select_rom_bank(value as usize);
+            }
             return;
         }
 
         // OAM disabled in modes 2 and 3
-        if (OAM as u16..OAM as u16 + OAM_LENGTH as u16).contains(&address) && !self.oam_enable {
+        if (OAM as u16..OAM as u16 + OAM_LENGTH).contains(&address) && !self.oam_enable {
             return;
         }
 
@@ -63,6 +405,59 @@ impl MMU {
         self.memory[address as usize] = value;
     }
 
+    // Cartridge ROM only: the boot ROM isn't in the log
+    fn cover(&self, address: u16, flag: u8) {
+        let Some(coverage) = &self.coverage else {
+            return;
+        };
+        if address as usize >= VRAM || (self.boot_rom_enabled && self.is_boot_rom_address(address))
+        {
+            return;
+        }
+        coverage.mark(self.mapped_rom_offset(address), flag);
+    }
+
+    fn watch(&self, address: u16, access: Access, value: u8) {
+        if self.watch_hit.get().is_none() && self.watches(address, access) {
+            self.watch_hit.set(Some((address, access, value)));
+        }
+    }
+
+    pub fn watches(&self, address: u16, access: Access) -> bool {
+        self.watchpoints.iter().any(|w| w.matches(address, access))
+    }
+
+    /// The first watched read or write since the last call: address, access and value.
+    pub fn take_watch_hit(&self) -> Option<(u16, Access, u8)> {
+        self.watch_hit.take()
+    }
+
+    /// Advances the serial port by `cycles` M-cycles.
+    pub fn step_serial(&mut self, cycles: u32) -> io::Result<()> {
+        if let Some(byte) = self.serial.step(cycles)? {
+            self.serial_sink.transmit(byte);
+            self.request_interrupt(InterruptCode::Serial);
+        }
+        Ok(())
+    }
+
+    pub fn increment_div(&mut self) {
+        let address = ControlRegisters::DIV as usize;
+        let old = self.memory[address];
+        self.memory[address] = old.wrapping_add(1);
+        self.apu.div_changed(old, self.memory[address]);
+    }
+
+    pub fn request_interrupt(&mut self, code: InterruptCode) {
+        self.memory[ControlRegisters::IF as usize] |= 1 << code as u8;
+    }
+
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.request_interrupt(InterruptCode::Joypad);
+        }
+    }
+
     pub fn read_word(&self, address: u16) -> u16 {
         let low_byte = self.read_byte(address) as u16;
         let high_byte = self.read_byte(address.wrapping_add(1)) as u16;
@@ -78,9 +473,233 @@ impl MMU {
         self.write_byte(address + 1, high_byte);
     }
 
-    pub fn read_rom(&mut self, file_path: &str) {
-        let mut file = File::open(file_path).expect("Error al abrir la ROM");
-        file.read(&mut self.memory[..])
-            .expect("Error al cargar la ROM en memoria");
+    pub fn read_rom<P: AsRef<Path>>(&mut self, file_path: P) -> io::Result<()> {
+        let mut rom = Vec::new();
+        File::open(file_path)?.read_to_end(&mut rom)?;
+        self.load_rom_data(rom);
+        Ok(())
+    }
+
+    /// Maps a ROM image: bank 0 at 0x0000 and bank 1 at 0x4000.
+    pub fn load_rom_data(&mut self, rom: Vec<u8>) {
+        let len = rom.len().min(VRAM);
+        self.memory[..len].copy_from_slice(&rom[..len]);
+        self.rom_crc32 = crc32(&rom);
+        self.rom = rom;
+        self.rom_bank = 1;
+    }
+
+    // GBS banking: only the low byte of the bank number, as on MBC5. Bank 0 selects bank 1, as
+    // on MBC1
+    fn select_rom_bank(&mut self, bank: usize) {
+        let banks = self.rom.len().div_ceil(ROM_BANK_LENGTH);
+        if banks <= 2 {
+            return;
+        }
+        let bank = (bank % banks).max(1);
+        if bank == self.rom_bank {
+            return;
+        }
+        let start = bank * ROM_BANK_LENGTH;
+        let end = (start + ROM_BANK_LENGTH).min(self.rom.len());
+        self.memory[ROM_BANK_1..ROM_BANK_1 + end - start].copy_from_slice(&self.rom[start..end]);
+        self.memory[ROM_BANK_1 + end - start..VRAM].fill(0xFF);
+        self.rom_bank = bank;
+    }
+
+    // The bank in `memory` and `other_vram` trade places
+    fn select_vram_bank(&mut self, bank: usize) {
+        if bank != self.vram_bank {
+            self.memory[VRAM..CARTRIDGE_RAM].swap_with_slice(&mut self.other_vram);
+            self.vram_bank = bank;
+        }
+    }
+
+    pub fn load_boot_rom<P: AsRef<Path>>(&mut self, file_path: P) -> io::Result<()> {
+        let mut boot_rom = Vec::new();
+        File::open(file_path)?.read_to_end(&mut boot_rom)?;
+        if boot_rom.len() != BOOT_ROM_LENGTH && boot_rom.len() != CGB_BOOT_ROM_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "boot ROM must be {} (DMG) or {} (CGB) bytes, got {}",
+                    BOOT_ROM_LENGTH,
+                    CGB_BOOT_ROM_LENGTH,
+                    boot_rom.len()
+                ),
+            ));
+        }
+        self.boot_rom = boot_rom;
+        self.boot_rom_enabled = true;
+
+        // The boot ROM sets the IO registers up itself
+        self.memory[IO_REGISTERS..HIGH_RAM].fill(0);
+        self.apu = Apu::new(self.model);
+        self.serial.write(serial::SB, 0);
+        self.serial.write(serial::SC, 0);
+        self.memory[ControlRegisters::IE as usize] = 0;
+        Ok(())
+    }
+
+    pub fn has_battery(&self) -> bool {
+        matches!(
+            self.memory[CARTRIDGE_TYPE],
+            0x03 | 0x06 | 0x09 | 0x0D | 0x0F | 0x10 | 0x13 | 0x1B | 0x1E | 0x22 | 0xFF
+        )
+    }
+
+    pub fn cartridge_ram(&self) -> &[u8] {
+        &self.memory[CARTRIDGE_RAM..CARTRIDGE_RAM + CARTRIDGE_RAM_LENGTH]
+    }
+
+    pub fn load_cartridge_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(CARTRIDGE_RAM_LENGTH);
+        self.memory[CARTRIDGE_RAM..CARTRIDGE_RAM + len].copy_from_slice(&data[..len]);
+    }
+}
+
+// The ROM isn't saved, only which bank is mapped. The serial sink isn't state either
+impl SaveState for MMU {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.memory);
+        w.bool(self.oam_enable);
+        w.bool(self.vram_enable);
+        w.bool(self.boot_rom_enabled);
+        w.vec(&self.boot_rom);
+        w.u32(self.rom_bank as u32);
+        w.u8(self.vram_bank as u8);
+        w.bytes(&self.other_vram);
+        self.joypad.save_state(w);
+        self.serial.save_state(w);
+        self.apu.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        r.read_into(&mut self.memory)?;
+        self.oam_enable = r.bool()?;
+        self.vram_enable = r.bool()?;
+        self.boot_rom_enabled = r.bool()?;
+        self.boot_rom = r.vec()?;
+        // Bank 0x4000-0x7FFF is in `memory` already
+        self.rom_bank = r.u32()? as usize;
+        self.vram_bank = (r.u8()? & 1) as usize;
+        r.read_into(&mut self.other_vram)?;
+        self.joypad.load_state(r)?;
+        self.serial.load_state(r)?;
+        self.apu.load_state(r)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    // Every byte of a bank is its number
+    fn mmu_with_banks(banks: usize) -> MMU {
+        let mut mmu = MMU::new(Model::DMG);
+        let rom = (0..banks * ROM_BANK_LENGTH)
+            .map(|i| (i / ROM_BANK_LENGTH) as u8)
+            .collect();
+        mmu.load_rom_data(rom);
+        mmu
+    }
+
+    #[test]
+    fn cartridges_ignore_bank_writes() {
+        let mut mmu = mmu_with_banks(4);
+        mmu.write_byte(0x2000, 2);
+        assert_eq!(mmu.rom_bank, 1);
+        assert_eq!(mmu.peek(0x4000), 1);
+    }
+
+    #[test]
+    fn gbs_cartridge_switches_banks() {
+        let mut mmu = mmu_with_banks(4);
+        mmu.rom_bank_switching = true;
+        mmu.write_byte(0x2000, 3);
+        assert_eq!((mmu.rom_bank, mmu.peek(0x7FFF)), (3, 3));
+        mmu.write_byte(0x3FFF, 0); // Bank 0 is bank 1
+        assert_eq!((mmu.rom_bank, mmu.peek(0x4000)), (1, 1));
+        mmu.write_byte(0x2000, 6); // Wraps around the ROM size
+        assert_eq!((mmu.rom_bank, mmu.peek(0x4000)), (2, 2));
+        assert_eq!(mmu.peek_banked(3, 0x4000), Some(3));
+    }
+
+    #[test]
+    fn p1_reads_0xcf_after_boot() {
+        for model in Model::ALL {
+            assert_eq!(MMU::new(model).read_byte(joypad::P1), 0xCF, "{}", model);
+        }
+    }
+
+    #[test]
+    fn joypad_interrupt_on_a_selected_line_going_low() {
+        let mut mmu = MMU::new(Model::DMG);
+        let if_joypad = |mmu: &MMU| mmu.read_byte(0xFF0F) & 0x10;
+        mmu.write_byte(0xFF0F, 0x00);
+        mmu.write_byte(joypad::P1, 0x20); // D-pad
+        mmu.set_button(Button::A, true);
+        assert_eq!(if_joypad(&mmu), 0);
+        mmu.set_button(Button::Down, true);
+        assert_eq!(if_joypad(&mmu), 0x10);
+        assert_eq!(mmu.read_byte(joypad::P1), 0xE7);
+
+        // Selecting the buttons pulls A's line low too
+        mmu.write_byte(0xFF0F, 0x00);
+        mmu.write_byte(joypad::P1, 0x00);
+        assert_eq!(if_joypad(&mmu), 0x10);
+        assert_eq!(mmu.read_byte(joypad::P1), 0xC6);
+    }
+
+    #[test]
+    fn cgb_banks_vram() {
+        let mut mmu = MMU::new(Model::CGB);
+        assert_eq!(mmu.read_byte(VBK), 0xFE);
+        mmu.write_byte(0x8000, 0xAA);
+        mmu.write_byte(VBK, 0xFF);
+        assert_eq!((mmu.read_byte(VBK), mmu.mapped_bank(0x9FFF)), (0xFF, 1));
+        assert_eq!(mmu.read_byte(0x8000), 0x00);
+        mmu.write_byte(0x8000, 0xBB);
+        assert_eq!(mmu.peek_banked(0, 0x8000), Some(0xAA));
+        assert!(mmu.poke_banked(0, 0x9FFF, 0xCC));
+
+        mmu.write_byte(VBK, 0x00);
+        assert_eq!((mmu.read_byte(0x8000), mmu.read_byte(0x9FFF)), (0xAA, 0xCC));
+        assert_eq!(mmu.peek_banked(1, 0x8000), Some(0xBB));
+        assert_eq!(mmu.peek_banked(2, 0x8000), None);
+    }
+
+    #[test]
+    fn dmg_has_a_single_vram_bank() {
+        let mut mmu = MMU::new(Model::DMG);
+        mmu.write_byte(0x8000, 0xAA);
+        mmu.write_byte(VBK, 0x01);
+        assert_eq!((mmu.vram_bank, mmu.read_byte(0x8000)), (0, 0xAA));
+        assert_eq!(mmu.peek_banked(1, 0x8000), None);
+    }
+
+    #[test]
+    fn coverage_marks_the_banked_rom_offset() {
+        let mut mmu = mmu_with_banks(4);
+        mmu.rom_bank_switching = true;
+        mmu.coverage = Some(Coverage::new(mmu.rom.len()));
+        mmu.write_byte(0x2000, 3);
+        for (i, byte) in [0xFA, 0x10, 0x40].into_iter().enumerate() {
+            mmu.poke(0x4000 + i as u16, byte); // LD A,($4010)
+        }
+        let mut cpu = CPU::new(Model::DMG);
+        cpu.registers.pc = 0x4000;
+        cpu.step(&mut mmu);
+        assert_eq!(cpu.registers.a, 3);
+
+        let coverage = mmu.coverage.as_ref().unwrap();
+        let bank_3 = 3 * ROM_BANK_LENGTH;
+        for offset in bank_3..bank_3 + 3 {
+            assert_eq!(coverage.flags(offset), coverage::CODE);
+        }
+        assert_eq!(coverage.flags(bank_3 + 0x10), coverage::DATA);
+        assert_eq!(coverage.flags(ROM_BANK_LENGTH + 0x10), 0); // Bank 1, not mapped
+        assert_eq!(coverage.flags(0x2000), coverage::WRITTEN);
     }
 }
@@ -0,0 +1,122 @@
+use std::fmt;
+use std::str::FromStr;
+
+/// Game Boy hardware revision. Selects the state the boot ROM leaves behind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Model {
+    DMG0, // Early original Game Boy
+    #[default]
+    DMG, // Original Game Boy
+    MGB,  // Game Boy Pocket / Light
+    SGB,  // Super Game Boy
+    SGB2, // Super Game Boy 2
+    CGB,  // Game Boy Color
+    AGB,  // Game Boy Advance in CGB mode
+}
+
+impl Model {
+    pub const ALL: [Model; 7] = [
+        Model::DMG0,
+        Model::DMG,
+        Model::MGB,
+        Model::SGB,
+        Model::SGB2,
+        Model::CGB,
+        Model::AGB,
+    ];
+
+    pub fn is_cgb(self) -> bool {
+        matches!(self, Model::CGB | Model::AGB)
+    }
+
+    pub fn is_sgb(self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Model::DMG0 => "dmg0",
+            Model::DMG => "dmg",
+            Model::MGB => "mgb",
+            Model::SGB => "sgb",
+            Model::SGB2 => "sgb2",
+            Model::CGB => "cgb",
+            Model::AGB => "agb",
+        }
+    }
+
+    /// DIV value and the M-cycles already elapsed towards its next increment when the boot ROM hands over.
+    /// Unknown phases are left at 0.
+    pub fn div_phase(self) -> (u8, u32) {
+        match self {
+            Model::DMG0 => (0x18, 0),
+            Model::DMG | Model::MGB => (0xAB, 0xCC / 4), // Internal counter 0xABCC
+            Model::SGB | Model::SGB2 => (0x00, 0),
+            Model::CGB | Model::AGB => (0x00, 0),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Model::ALL
+            .into_iter()
+            .find(|model| model.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                let names: Vec<&str> = Model::ALL.iter().map(|m| m.name()).collect();
+                format!(
+                    "unknown model '{}', expected one of {}",
+                    s,
+                    names.join(", ")
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::{ControlRegisters, CPU};
+    use crate::mmu::MMU;
+
+    #[test]
+    fn parses_model_names() {
+        for model in Model::ALL {
+            assert_eq!(model.name().parse(), Ok(model));
+        }
+        assert_eq!("CGB".parse(), Ok(Model::CGB));
+        assert_eq!("Sgb2".parse(), Ok(Model::SGB2));
+        assert_eq!(
+            "gba".parse::<Model>(),
+            Err("unknown model 'gba', expected one of dmg0, dmg, mgb, sgb, sgb2, cgb, agb".into())
+        );
+        assert!("".parse::<Model>().is_err());
+    }
+
+    #[test]
+    fn div_starts_at_the_boot_rom_phase() {
+        assert_eq!(Model::DMG0.div_phase(), (0x18, 0));
+        assert_eq!(Model::DMG.div_phase(), (0xAB, 51));
+        assert_eq!(Model::MGB.div_phase(), Model::DMG.div_phase());
+        for model in Model::ALL {
+            let (div, cycles) = model.div_phase();
+            assert!(cycles < 64, "{}", model); // DIV ticks every 64 M-cycles
+            let mmu = MMU::new(model);
+            assert_eq!(
+                mmu.read_byte(ControlRegisters::DIV as u16),
+                div,
+                "{}",
+                model
+            );
+            assert_eq!(CPU::new(model).div_counter, cycles, "{}", model);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::model::Model;
+
+/*
+Input movie: the joypad state of every frame since power on.
+
+Text format, one entry per line:
+    RUSTBOY-MOVIE 1
+    model dmg
+    rom-crc32 1A2B3C4D
+    frames
+    00            <- pressed buttons of frame 0, bit n = `Button` n
+    10
+    ...
+*/
+
+const MAGIC: &str = "RUSTBOY-MOVIE";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    pub model: Model,
+    pub rom_crc32: u32,
+    pub frames: Vec<u8>, // Pressed buttons per frame, same layout as `Joypad::pressed`
+}
+
+impl Movie {
+    pub fn new(model: Model, rom_crc32: u32) -> Self {
+        Movie {
+            model,
+            rom_crc32,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!(
+            "{} {}\nmodel {}\nrom-crc32 {:08X}\nframes\n",
+            MAGIC, VERSION, self.model, self.rom_crc32
+        );
+        for input in &self.frames {
+            text.push_str(&format!("{:02X}\n", input));
+        }
+        text
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn parse(text: &str) -> Result<Self, String> {
+        let mut lines = text.lines();
+        let mut header = |key: &str| -> Result<String, String> {
+            let line = lines.next().ok_or("unexpected end of movie")?;
+            line.strip_prefix(key)
+                .map(|value| value.trim().to_string())
+                .ok_or_else(|| format!("expected '{}', found '{}'", key, line))
+        };
+
+        let version = header(MAGIC)?;
+        if version != VERSION.to_string() {
+            return Err(format!("unsupported movie version {}", version));
+        }
+        let model = header("model")?.parse()?;
+        let crc = header("rom-crc32")?;
+        let rom_crc32 =
+            u32::from_str_radix(&crc, 16).map_err(|_| format!("invalid ROM CRC '{}'", crc))?;
+        header("frames")?;
+
+        let frames = lines
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                u8::from_str_radix(line.trim(), 16)
+                    .map_err(|_| format!("invalid frame input '{}'", line))
+            })
+            .collect::<Result<_, _>>()?;
+
+        Ok(Movie {
+            model,
+            rom_crc32,
+            frames,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_through_text() {
+        let mut movie = Movie::new(Model::CGB, 0x1A2B3C4D);
+        movie.frames = vec![0x00, 0x10, 0xFF];
+        let text = movie.to_text();
+        assert_eq!(
+            text,
+            "RUSTBOY-MOVIE 1\nmodel cgb\nrom-crc32 1A2B3C4D\nframes\n00\n10\nFF\n"
+        );
+        assert_eq!(Movie::parse(&text), Ok(movie));
+    }
+
+    #[test]
+    fn skips_blank_frame_lines() {
+        let movie = Movie::parse("RUSTBOY-MOVIE 1\nmodel dmg\nrom-crc32 0\nframes\n01\n\n 02 \n");
+        assert_eq!(movie.map(|movie| movie.frames), Ok(vec![0x01, 0x02]));
+    }
+
+    #[test]
+    fn rejects_bad_movies() {
+        let parse = |text: &str| Movie::parse(text).unwrap_err();
+        assert_eq!(parse("RUSTBOY-MOVIE 2\n"), "unsupported movie version 2");
+        assert_eq!(parse("RUSTBOY-MOVIE 1\n"), "unexpected end of movie");
+        assert_eq!(
+            parse("RUSTBOY-MOVIE 1\nframes\n"),
+            "expected 'model', found 'frames'"
+        );
+        assert!(parse("RUSTBOY-MOVIE 1\nmodel nes\n").starts_with("unknown model 'nes'"));
+        assert_eq!(
+            parse("RUSTBOY-MOVIE 1\nmodel dmg\nrom-crc32 XYZ\n"),
+            "invalid ROM CRC 'XYZ'"
+        );
+        assert_eq!(
+            parse("RUSTBOY-MOVIE 1\nmodel dmg\nrom-crc32 0\nframes\n100\n"),
+            "invalid frame input '100'"
+        );
+    }
+}
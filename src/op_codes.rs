@@ -1,4 +1,4 @@
-use crate::{mmu::MMU, CPU};
+use crate::{cpu::CPU, mmu::MMU};
 
 pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
     let opcode = cpu.fetch_byte(mmu);
@@ -382,7 +382,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x40 => {
             // LD B, B
-            cpu.registers.b = cpu.registers.b;
             1
         }
         0x41 => {
@@ -427,7 +426,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x49 => {
             // LD C, C
-            cpu.registers.c = cpu.registers.c;
             1
         }
         0x4A => {
@@ -472,7 +470,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x52 => {
             // LD D, D
-            cpu.registers.d = cpu.registers.d;
             1
         }
         0x53 => {
@@ -517,7 +514,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x5B => {
             // LD E, E
-            cpu.registers.e = cpu.registers.e;
             1
         }
         0x5C => {
@@ -562,7 +558,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x64 => {
             // LD h, h
-            cpu.registers.h = cpu.registers.h;
             1
         }
         0x65 => {
@@ -607,7 +602,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x6D => {
             // LD l, l
-            cpu.registers.l = cpu.registers.l;
             1
         }
         0x6E => {
@@ -697,7 +691,6 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
         }
         0x7F => {
             // LD A, A
-            cpu.registers.a = cpu.registers.a;
             1
         }
         0x80 => {
@@ -1395,7 +1388,14 @@ pub fn execute_opcode(cpu: &mut CPU, mmu: &mut MMU) -> u8 {
             cpu.rst(0x38, mmu);
             4
         }
-        _ => panic!("Unknown opcode: 0x{:X}", opcode),
+        _ => {
+            // The real CPU locks up here. Leave PC on the opcode for the stack trace
+            cpu.registers.pc = cpu.registers.pc.wrapping_sub(1);
+            panic!(
+                "Illegal opcode 0x{:02X} at 0x{:04X}",
+                opcode, cpu.registers.pc
+            );
+        }
     }
 }
 
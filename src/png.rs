@@ -0,0 +1,124 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use crate::checksum::{adler32, crc32_update};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// Writes an 8-bit RGB image, `pixels` row by row. The image data is stored uncompressed:
+/// screenshots of the Game Boy are small enough.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    width: u32,
+    height: u32,
+    pixels: &[[u8; 3]],
+) -> io::Result<()> {
+    assert_eq!(pixels.len(), width as usize * height as usize);
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), deflate, adaptive filtering, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut out, b"IHDR", &header)?;
+
+    // Every row starts with its filter type, 0 = None
+    let mut raw = Vec::with_capacity(pixels.len() * 3 + height as usize);
+    for row in pixels.chunks(width.max(1) as usize) {
+        raw.push(0);
+        raw.extend(row.iter().flatten());
+    }
+    write_chunk(&mut out, b"IDAT", &zlib_stored(&raw))?;
+    write_chunk(&mut out, b"IEND", &[])?;
+    out.flush()
+}
+
+fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32_update(crc32_update(0, kind), data);
+    out.write_all(&crc.to_be_bytes())
+}
+
+// zlib stream made of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01]; // Deflate, 32K window, no preset dictionary
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        out.push(last as u8); // BFINAL, BTYPE 00 = stored
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::crc32;
+    use std::fs;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    // Kind and data of every chunk, checking lengths and CRCs on the way
+    fn chunks(mut data: &[u8]) -> Vec<(String, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let length = read_u32(data, 0) as usize;
+            let (body, crc) = data[4..].split_at(4 + length);
+            assert_eq!(read_u32(crc, 0), crc32(body));
+            let kind = String::from_utf8(body[..4].to_vec()).unwrap();
+            chunks.push((kind, body[4..].to_vec()));
+            data = &crc[4..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn writes_a_valid_png() {
+        let path = std::env::temp_dir().join(format!("rustboy-{}.png", std::process::id()));
+        let pixels = [[0xFF, 0x00, 0x00], [0x00, 0xFF, 0x00], [0x00, 0x00, 0xFF]];
+        save(&path, 1, 3, &pixels).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(data[..8], SIGNATURE);
+        let chunks = chunks(&data[8..]);
+        let kinds: Vec<&str> = chunks.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 1, 0, 0, 0, 3, 8, 2, 0, 0, 0]);
+        let raw = [0, 0xFF, 0, 0, 0, 0, 0xFF, 0, 0, 0, 0, 0xFF];
+        assert_eq!(chunks[1].1, zlib_stored(&raw));
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn stores_zlib_blocks() {
+        assert_eq!(
+            zlib_stored(&[]),
+            [0x78, 0x01, 0x01, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x01]
+        );
+
+        let data = vec![0xAB; MAX_STORED_BLOCK + 1];
+        let out = zlib_stored(&data);
+        assert_eq!(out.len(), 2 + 5 + MAX_STORED_BLOCK + 5 + 1 + 4);
+        assert_eq!(out[2..7], [0x00, 0xFF, 0xFF, 0x00, 0x00]); // Not the last block
+        let second = 7 + MAX_STORED_BLOCK;
+        assert_eq!(out[second..second + 5], [0x01, 0x01, 0x00, 0xFE, 0xFF]);
+        assert_eq!(out[out.len() - 4..], adler32(&data).to_be_bytes());
+    }
+}
@@ -0,0 +1,376 @@
+use std::collections::VecDeque;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::png;
+use crate::serial::{Link, Packet};
+
+/*
+Game Boy Printer packet, sent by the Game Boy (always the clock master):
+0x88 0x33          Magic
+Command            0x01 init, 0x02 print, 0x04 data, 0x0F status
+Compression        1 = data is RLE compressed
+Length             2 bytes, little-endian. Length of the data as sent
+Data
+Checksum           2 bytes, little-endian. Sum of every byte from the command to the data
+0x00 0x00          The printer answers 0x81 (device ID), then its status
+
+Every other byte is answered with 0x00.
+
+Print data (4 bytes): sheets (0 = just feed paper), margins (high nibble before,
+low nibble after; 0 after means the next print continues the same page),
+palette (2 bits per color, color 0 in the low bits) and exposure.
+*/
+
+const MAGIC: [u8; 2] = [0x88, 0x33];
+const DEVICE_ID: u8 = 0x81;
+
+const INIT: u8 = 0x01;
+const PRINT: u8 = 0x02;
+const DATA: u8 = 0x04;
+const STATUS: u8 = 0x0F;
+
+// Status bits
+const CHECKSUM_ERROR: u8 = 0x01;
+const PRINTING: u8 = 0x02;
+const IMAGE_DATA_FULL: u8 = 0x04;
+const UNPROCESSED_DATA: u8 = 0x08;
+
+const BUFFER_LENGTH: usize = 0x2280; // 9 bands
+const BAND_LENGTH: usize = 0x280; // 2 rows of 20 tiles
+const WIDTH: usize = 160;
+const PRINT_POLLS: u8 = 4; // Status packets that still report "printing" after a print
+
+const SHADES: [u8; 4] = [0xFF, 0xAA, 0x55, 0x00];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Magic(usize),
+    Command,
+    Compression,
+    Length(usize),
+    Data,
+    Checksum(usize),
+    DeviceId,
+    Status,
+}
+
+/// Game Boy Printer on the link port. Finished pages are saved as `print-NNN.png` in a directory,
+/// and so is a page still being printed when the printer is dropped.
+pub struct Printer {
+    dir: PathBuf,
+    state: State,
+    command: u8,
+    compressed: bool,
+    length: u16,
+    data: Vec<u8>,
+    checksum: u16, // Sum of the packet so far
+    received_checksum: u16,
+    buffer: Vec<u8>, // Tile data waiting to be printed
+    page: Vec<u8>,   // Shades of the page being printed, 160 pixels per line
+    status: u8,
+    print_polls: u8,
+    pages: u32,
+    replies: VecDeque<u8>,
+}
+
+impl Printer {
+    pub fn new<P: AsRef<Path>>(dir: P) -> Self {
+        Printer {
+            dir: dir.as_ref().to_path_buf(),
+            state: State::Magic(0),
+            command: 0,
+            compressed: false,
+            length: 0,
+            data: Vec::new(),
+            checksum: 0,
+            received_checksum: 0,
+            buffer: Vec::new(),
+            page: Vec::new(),
+            status: 0,
+            print_polls: 0,
+            pages: 0,
+            replies: VecDeque::new(),
+        }
+    }
+
+    /// Takes one byte from the Game Boy and returns the byte shifted back.
+    fn receive_byte(&mut self, byte: u8) -> io::Result<u8> {
+        let mut reply = 0x00;
+        self.state = match self.state {
+            State::Magic(i) if byte == MAGIC[i] => {
+                if i == 0 {
+                    State::Magic(1)
+                } else {
+                    State::Command
+                }
+            }
+            State::Magic(_) if byte == MAGIC[0] => State::Magic(1),
+            State::Magic(_) => State::Magic(0),
+            State::Command => {
+                self.command = byte;
+                self.checksum = byte as u16;
+                State::Compression
+            }
+            State::Compression => {
+                self.compressed = byte & 0x01 != 0;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(0)
+            }
+            State::Length(0) => {
+                self.length = byte as u16;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                State::Length(1)
+            }
+            State::Length(_) => {
+                self.length |= (byte as u16) << 8;
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                self.data.clear();
+                if self.length == 0 {
+                    State::Checksum(0)
+                } else {
+                    State::Data
+                }
+            }
+            State::Data => {
+                self.data.push(byte);
+                self.checksum = self.checksum.wrapping_add(byte as u16);
+                if self.data.len() == self.length as usize {
+                    State::Checksum(0)
+                } else {
+                    State::Data
+                }
+            }
+            State::Checksum(0) => {
+                self.received_checksum = byte as u16;
+                State::Checksum(1)
+            }
+            State::Checksum(_) => {
+                self.received_checksum |= (byte as u16) << 8;
+                if self.received_checksum == self.checksum {
+                    self.status &= !CHECKSUM_ERROR;
+                    self.run_command()?;
+                } else {
+                    self.status |= CHECKSUM_ERROR;
+                }
+                State::DeviceId
+            }
+            State::DeviceId => {
+                reply = DEVICE_ID;
+                State::Status
+            }
+            State::Status => {
+                reply = self.status();
+                State::Magic(0)
+            }
+        };
+        Ok(reply)
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = self.status;
+        if self.print_polls > 0 {
+            status |= PRINTING;
+        }
+        if !self.buffer.is_empty() {
+            status |= UNPROCESSED_DATA;
+        }
+        if self.buffer.len() >= BUFFER_LENGTH {
+            status |= IMAGE_DATA_FULL;
+        }
+        status
+    }
+
+    fn run_command(&mut self) -> io::Result<()> {
+        match self.command {
+            INIT => {
+                self.buffer.clear();
+                self.status = 0;
+                self.print_polls = 0;
+            }
+            DATA => {
+                // An empty data packet just marks the end of the image
+                let data = if self.compressed {
+                    decompress(&self.data)
+                } else {
+                    std::mem::take(&mut self.data)
+                };
+                let room = BUFFER_LENGTH - self.buffer.len();
+                self.buffer.extend_from_slice(&data[..data.len().min(room)]);
+            }
+            PRINT if self.data.len() >= 4 => {
+                let (sheets, margins, palette) = (self.data[0], self.data[1], self.data[2]);
+                if sheets > 0 {
+                    self.render(palette);
+                }
+                self.buffer.clear();
+                self.print_polls = PRINT_POLLS;
+                // Feeding paper after the image ends the page
+                if margins & 0x0F != 0 && !self.page.is_empty() {
+                    self.save_page()?;
+                }
+            }
+            STATUS => self.print_polls = self.print_polls.saturating_sub(1),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    // 2bpp tiles, 20 per row, two rows per band. A short last band is padded with blank paper
+    fn render(&mut self, palette: u8) {
+        let lines = self.buffer.len().div_ceil(BAND_LENGTH) * 16;
+        let start = self.page.len();
+        self.page.resize(start + lines * WIDTH, SHADES[0]);
+
+        for (tile_index, tile) in self.buffer.chunks_exact(16).enumerate() {
+            let tile_x = tile_index % 20 * 8;
+            let tile_y = tile_index / 20 * 8;
+            for row in 0..8 {
+                let (low, high) = (tile[row * 2], tile[row * 2 + 1]);
+                for bit in 0..8 {
+                    let color = ((high >> (7 - bit)) & 1) << 1 | ((low >> (7 - bit)) & 1);
+                    let shade = (palette >> (color * 2)) & 0x03;
+                    let pixel = start + (tile_y + row) * WIDTH + tile_x + bit;
+                    if pixel < self.page.len() {
+                        self.page[pixel] = SHADES[shade as usize];
+                    }
+                }
+            }
+        }
+    }
+
+    fn save_page(&mut self) -> io::Result<()> {
+        let path = loop {
+            self.pages += 1;
+            let path = self.dir.join(format!("print-{:03}.png", self.pages));
+            if !path.exists() {
+                break path;
+            }
+        };
+        std::fs::create_dir_all(&self.dir)?;
+        let pixels: Vec<[u8; 3]> = self.page.iter().map(|&s| [s, s, s]).collect();
+        let height = (self.page.len() / WIDTH) as u32;
+        png::save(&path, WIDTH as u32, height, &pixels)?;
+        self.page.clear();
+        Ok(())
+    }
+}
+
+impl Drop for Printer {
+    fn drop(&mut self) {
+        // Emulation ended mid-job, or the game never fed paper after the image
+        if !self.page.is_empty() {
+            let _ = self.save_page(); // Nobody to tell
+        }
+    }
+}
+
+impl Link for Printer {
+    fn send(&mut self, packet: Packet) -> io::Result<()> {
+        // The printer never clocks the line, so it only ever sees transfers
+        if let Packet::Transfer(byte) = packet {
+            let reply = self.receive_byte(byte)?;
+            self.replies.push_back(reply);
+        }
+        Ok(())
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Packet>> {
+        Ok(self.replies.pop_front().map(Packet::Reply))
+    }
+}
+
+/*
+RLE: a byte with bit 7 set repeats the next byte (n & 0x7F) + 2 times,
+otherwise the next n + 1 bytes are copied as they are.
+*/
+fn decompress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let n = data[i] as usize;
+        i += 1;
+        if n & 0x80 != 0 {
+            if let Some(&byte) = data.get(i) {
+                out.extend(std::iter::repeat_n(byte, (n & 0x7F) + 2));
+            }
+            i += 1;
+        } else {
+            let end = (i + n + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn send_packet(printer: &mut Printer, command: u8, data: &[u8]) -> Vec<u8> {
+        let length = (data.len() as u16).to_le_bytes();
+        let mut packet = vec![MAGIC[0], MAGIC[1], command, 0, length[0], length[1]];
+        packet.extend_from_slice(data);
+        let checksum = packet[2..]
+            .iter()
+            .fold(0u16, |sum, &b| sum.wrapping_add(b as u16));
+        packet.extend_from_slice(&checksum.to_le_bytes());
+        packet.extend_from_slice(&[0, 0]);
+        packet
+            .into_iter()
+            .map(|byte| {
+                printer.send(Packet::Transfer(byte)).unwrap();
+                match printer.receive().unwrap() {
+                    Some(Packet::Reply(reply)) => reply,
+                    other => panic!("expected a reply, got {:?}", other),
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn decompresses_runs_and_literals() {
+        assert_eq!(
+            decompress(&[0x81, 0xAA, 0x01, 0x11, 0x22, 0x80, 0xFF]),
+            vec![0xAA, 0xAA, 0xAA, 0x11, 0x22, 0xFF, 0xFF]
+        );
+    }
+
+    #[test]
+    fn answers_with_device_id_and_status() {
+        let dir =
+            std::env::temp_dir().join(format!("rustboy-printer-{}-status", std::process::id()));
+        let mut printer = Printer::new(&dir);
+        let replies = send_packet(&mut printer, INIT, &[]);
+        assert_eq!(&replies[replies.len() - 2..], &[DEVICE_ID, 0]);
+        let replies = send_packet(&mut printer, DATA, &[0; 16]);
+        assert_eq!(replies[replies.len() - 1], UNPROCESSED_DATA);
+        drop(printer);
+        assert!(!dir.exists()); // Nothing printed
+    }
+
+    #[test]
+    fn saves_a_partial_band_when_dropped() {
+        let dir = std::env::temp_dir().join(format!("rustboy-printer-{}-drop", std::process::id()));
+        let mut printer = Printer::new(&dir);
+        send_packet(&mut printer, INIT, &[]);
+        // One row of tiles, half a band: color 3 in the first tile only
+        let mut data = vec![0; BAND_LENGTH / 2];
+        data[..16].fill(0xFF);
+        send_packet(&mut printer, DATA, &data);
+        send_packet(&mut printer, DATA, &[]);
+        // No margin after, so the page stays open
+        send_packet(&mut printer, PRINT, &[1, 0x10, 0xE4, 0x40]);
+        assert_eq!(printer.page.len(), 16 * WIDTH);
+        assert_eq!(printer.page[0], SHADES[3]);
+        assert_eq!(printer.page[8], SHADES[0]);
+        assert_eq!(printer.page[8 * WIDTH], SHADES[0]); // Padding
+        drop(printer);
+
+        let png = fs::read(dir.join("print-001.png")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(&png[12..24], b"IHDR\0\0\0\xA0\0\0\0\x10");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::callstack::{CallStack, Frame};
+use crate::symbols::Symbols;
+
+const ROOT: &str = "top"; // Folded stacks start here, code that isn't in any call
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Sample {
+    pub instructions: u64,
+    pub cycles: u64, // M-cycles, with HALT and interrupt dispatch
+}
+
+/*
+Counts what runs where: instructions and M-cycles per (bank, PC), and M-cycles per call stack.
+A call stack is the (bank, address) of every function entered, outermost first. The CPU feeds it
+through `enter` before each instruction and `record` after.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct Profiler {
+    samples: HashMap<(u16, u16), Sample>,
+    stacks: HashMap<Vec<(u16, u16)>, u64>,
+    stack: Vec<(u16, u16)>, // Key of the call stack the instruction runs in
+    stack_top: Option<(usize, Frame)>, // Depth and innermost frame `stack` was built from
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Before an instruction runs: takes the call stack it runs in.
+    pub fn enter(&mut self, call_stack: &CallStack) {
+        let frames = call_stack.frames();
+        let top = frames.last().map(|frame| (frames.len(), *frame));
+        // A call or return always changes the innermost frame, or how deep it is
+        if top != self.stack_top {
+            self.stack_top = top;
+            self.stack.clear();
+            self.stack
+                .extend(frames.iter().map(|frame| (frame.target_bank, frame.target)));
+        }
+    }
+
+    /// After the instruction at `pc` ran for `cycles`. `executed` is false for a step spent
+    /// halted.
+    pub fn record(&mut self, bank: u16, pc: u16, cycles: u32, executed: bool) {
+        let sample = self.samples.entry((bank, pc)).or_default();
+        sample.instructions += executed as u64;
+        sample.cycles += cycles as u64;
+        match self.stacks.get_mut(self.stack.as_slice()) {
+            Some(total) => *total += cycles as u64,
+            None => {
+                self.stacks.insert(self.stack.clone(), cycles as u64);
+            }
+        }
+    }
+
+    pub fn sample(&self, bank: u16, pc: u16) -> Sample {
+        self.samples.get(&(bank, pc)).copied().unwrap_or_default()
+    }
+
+    pub fn total(&self) -> Sample {
+        self.samples
+            .values()
+            .fold(Sample::default(), |total, sample| Sample {
+                instructions: total.instructions + sample.instructions,
+                cycles: total.cycles + sample.cycles,
+            })
+    }
+
+    /// Totals per bank, numbered as `MMU::mapped_bank` does.
+    pub fn banks(&self) -> Vec<(u16, Sample)> {
+        let mut banks: HashMap<u16, Sample> = HashMap::new();
+        for (&(bank, _), sample) in &self.samples {
+            let total = banks.entry(bank).or_default();
+            total.instructions += sample.instructions;
+            total.cycles += sample.cycles;
+        }
+        let mut banks: Vec<_> = banks.into_iter().collect();
+        banks.sort_by_key(|&(bank, _)| bank);
+        banks
+    }
+
+    /// Addresses by M-cycles spent, most first.
+    pub fn hot_spots(&self) -> Vec<((u16, u16), Sample)> {
+        let mut spots: Vec<_> = self.samples.iter().map(|(k, v)| (*k, *v)).collect();
+        spots.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        spots
+    }
+
+    /// Totals, M-cycles per bank and the `limit` hottest addresses.
+    pub fn write_report<W: Write>(
+        &self,
+        symbols: &Symbols,
+        limit: usize,
+        out: &mut W,
+    ) -> io::Result<()> {
+        let total = self.total();
+        let percent = |cycles: u64| 100.0 * cycles as f64 / total.cycles.max(1) as f64;
+        writeln!(
+            out,
+            "{} instructions, {} M-cycles",
+            total.instructions, total.cycles
+        )?;
+        writeln!(out, "Bank  M-cycles        %")?;
+        for (bank, sample) in self.banks() {
+            writeln!(
+                out,
+                "{:02X}    {:<12} {:5.1}",
+                bank,
+                sample.cycles,
+                percent(sample.cycles)
+            )?;
+        }
+        writeln!(out, "Address   M-cycles        %  Instructions  Label")?;
+        for ((bank, pc), sample) in self.hot_spots().into_iter().take(limit) {
+            let line = format!(
+                "{:02X}:{:04X}  {:<12} {:5.1}  {:<12}  {}",
+                bank,
+                pc,
+                sample.cycles,
+                percent(sample.cycles),
+                sample.instructions,
+                symbols.describe(bank, pc).unwrap_or_default()
+            );
+            writeln!(out, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// Folded stacks for flamegraph.pl or inferno, one per line: `top;Main;DrawSprites 1234`.
+    /// Functions are named by their labels, or by address without symbols. Weights are M-cycles.
+    pub fn write_folded<W: Write>(&self, symbols: &Symbols, out: &mut W) -> io::Result<()> {
+        // Stacks only told apart by bank can end up with the same names
+        let mut folded: HashMap<String, u64> = HashMap::new();
+        for (stack, cycles) in &self.stacks {
+            let mut line = String::from(ROOT);
+            for &(bank, address) in stack {
+                line.push(';');
+                match symbols.describe(bank, address) {
+                    Some(label) => line.push_str(&label),
+                    None => line.push_str(&format!("{:02X}:{:04X}", bank, address)),
+                }
+            }
+            *folded.entry(line).or_default() += cycles;
+        }
+        let mut lines: Vec<_> = folded.into_iter().collect();
+        lines.sort();
+        for (line, cycles) in lines {
+            writeln!(out, "{} {}", line, cycles)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::callstack::FrameKind;
+
+    fn frame(target_bank: u16, target: u16, sp: u16) -> Frame {
+        Frame {
+            kind: FrameKind::Call,
+            call_site: 0,
+            bank: 0,
+            target,
+            target_bank,
+            sp,
+        }
+    }
+
+    fn folded(profiler: &Profiler, symbols: &Symbols) -> String {
+        let mut out = Vec::new();
+        profiler.write_folded(symbols, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // Main calls Draw in bank 1, then the same address in bank 2
+    fn profile() -> Profiler {
+        let mut profiler = Profiler::new();
+        let mut stack = CallStack::new();
+        profiler.enter(&stack);
+        profiler.record(0, 0x0100, 4, true);
+        stack.call(frame(0, 0x0150, 0xFFFC));
+        profiler.enter(&stack);
+        profiler.record(0, 0x0150, 2, true);
+        stack.call(frame(1, 0x4000, 0xFFFA));
+        profiler.enter(&stack);
+        profiler.record(1, 0x4000, 3, true);
+        profiler.record(1, 0x4000, 1, false); // Halted
+        stack.ret(0xFFFA);
+        stack.call(frame(2, 0x4000, 0xFFFA));
+        profiler.enter(&stack);
+        profiler.record(2, 0x4000, 5, true);
+        profiler
+    }
+
+    #[test]
+    fn counts_per_address_and_bank() {
+        let profiler = profile();
+        let sample = profiler.sample(1, 0x4000);
+        assert_eq!((sample.instructions, sample.cycles), (1, 4));
+        let total = profiler.total();
+        assert_eq!((total.instructions, total.cycles), (4, 15));
+        let banks: Vec<_> = profiler
+            .banks()
+            .iter()
+            .map(|(b, s)| (*b, s.cycles))
+            .collect();
+        assert_eq!(banks, [(0, 6), (1, 4), (2, 5)]);
+        assert_eq!(profiler.hot_spots()[0].0, (2, 0x4000));
+    }
+
+    #[test]
+    fn folds_stacks_by_address_without_symbols() {
+        let folded = folded(&profile(), &Symbols::new());
+        assert_eq!(
+            folded,
+            "top 4\n\
+             top;00:0150 2\n\
+             top;00:0150;01:4000 4\n\
+             top;00:0150;02:4000 5\n"
+        );
+    }
+
+    #[test]
+    fn folds_stacks_with_the_same_names_together() {
+        let symbols = Symbols::parse("00:0150 Main\n01:4000 Draw\n02:4000 Draw").unwrap();
+        let folded = folded(&profile(), &symbols);
+        assert_eq!(folded, "top 4\ntop;Main 2\ntop;Main;Draw 9\n");
+    }
+}
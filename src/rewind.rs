@@ -0,0 +1,303 @@
+use std::collections::VecDeque;
+use std::io;
+
+use crate::gameboy::GameBoy;
+
+pub const DEFAULT_INTERVAL: u64 = 60; // Frames between snapshots, about a second
+pub const DEFAULT_MEMORY: usize = 16 * 1024 * 1024;
+const KEYFRAME_INTERVAL: usize = 30; // Snapshots per keyframe
+
+/*
+Snapshots are save states. A keyframe is stored whole, every other snapshot as the XOR with
+the last keyframe: most of the machine doesn't change in a few seconds, so that is mostly zeros.
+Both are then compressed with `compress`.
+*/
+
+struct Snapshot {
+    frame: u64,
+    keyframe: bool,
+    len: usize, // Uncompressed length
+    data: Vec<u8>,
+}
+
+/// Ring buffer of snapshots taken every few frames, to step back in time.
+pub struct Rewind {
+    interval: u64,
+    max_memory: usize,
+    snapshots: VecDeque<Snapshot>,
+    memory: usize,             // Compressed bytes held
+    keyframe: Option<Vec<u8>>, // Keyframe new deltas are taken against, uncompressed
+    since_keyframe: usize,
+}
+
+impl Rewind {
+    /// Keeps a snapshot every `interval` frames, forgetting the oldest ones beyond `max_memory`
+    /// bytes.
+    pub fn new(interval: u64, max_memory: usize) -> Self {
+        Rewind {
+            interval: interval.max(1),
+            max_memory,
+            snapshots: VecDeque::new(),
+            memory: 0,
+            keyframe: None,
+            since_keyframe: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    /// Compressed bytes used by the snapshots.
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Frames that can be rewound to, oldest first.
+    pub fn frames(&self) -> impl Iterator<Item = u64> + '_ {
+        self.snapshots.iter().map(|snapshot| snapshot.frame)
+    }
+
+    /// Takes a snapshot if one is due at this frame. Call once per frame.
+    pub fn record(&mut self, gameboy: &GameBoy) {
+        let frame = gameboy.frame_count;
+        if !frame.is_multiple_of(self.interval)
+            || self.snapshots.back().is_some_and(|s| s.frame >= frame)
+        {
+            return;
+        }
+        let state = gameboy.save_state();
+
+        let snapshot = match &self.keyframe {
+            Some(keyframe) if self.since_keyframe < KEYFRAME_INTERVAL => {
+                self.since_keyframe += 1;
+                Snapshot {
+                    frame,
+                    keyframe: false,
+                    len: state.len(),
+                    data: compress(&xor(&state, keyframe)),
+                }
+            }
+            _ => {
+                self.since_keyframe = 0;
+                let snapshot = Snapshot {
+                    frame,
+                    keyframe: true,
+                    len: state.len(),
+                    data: compress(&state),
+                };
+                self.keyframe = Some(state);
+                snapshot
+            }
+        };
+        self.memory += snapshot.data.len();
+        self.snapshots.push_back(snapshot);
+        self.evict();
+    }
+
+    // Oldest first. Deltas can't be restored without their keyframe, so they go with it
+    fn evict(&mut self) {
+        while self.memory > self.max_memory && self.snapshots.len() > 1 {
+            self.pop_front();
+            while self.snapshots.front().is_some_and(|s| !s.keyframe) {
+                self.pop_front();
+            }
+        }
+        if self.snapshots.is_empty() {
+            self.keyframe = None;
+        }
+    }
+
+    fn pop_front(&mut self) {
+        if let Some(snapshot) = self.snapshots.pop_front() {
+            self.memory -= snapshot.data.len();
+        }
+    }
+
+    /// Goes back to the latest snapshot older than the current frame, and forgets it so the
+    /// next call goes further back. Returns false when there is nothing left.
+    pub fn step_back(&mut self, gameboy: &mut GameBoy) -> io::Result<bool> {
+        while self
+            .snapshots
+            .back()
+            .is_some_and(|s| s.frame >= gameboy.frame_count)
+        {
+            self.pop_back();
+        }
+        let Some(snapshot) = self.snapshots.back() else {
+            return Ok(false);
+        };
+
+        let mut state = decompress(&snapshot.data, snapshot.len);
+        if !snapshot.keyframe {
+            let keyframe = self
+                .snapshots
+                .iter()
+                .rev()
+                .find(|s| s.keyframe)
+                .expect("eviction keeps a keyframe before every delta");
+            state = xor(&state, &decompress(&keyframe.data, keyframe.len));
+        }
+        gameboy.load_state(&state)?;
+        self.pop_back();
+        Ok(true)
+    }
+
+    fn pop_back(&mut self) {
+        if let Some(snapshot) = self.snapshots.pop_back() {
+            self.memory -= snapshot.data.len();
+            if snapshot.keyframe {
+                // Deltas need a keyframe that is still there: the next snapshot starts afresh
+                self.keyframe = None;
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.memory = 0;
+        self.keyframe = None;
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL, DEFAULT_MEMORY)
+    }
+}
+
+// As long as `data`; a shorter `base` counts as zeros past its end
+fn xor(data: &[u8], base: &[u8]) -> Vec<u8> {
+    data.iter()
+        .enumerate()
+        .map(|(i, byte)| byte ^ base.get(i).copied().unwrap_or(0))
+        .collect()
+}
+
+/*
+Zero run-length encoding: pairs of (zeros, literals) counts, u16 each, the literal bytes
+after every pair.
+*/
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let zeros = data[i..]
+            .iter()
+            .take(u16::MAX as usize)
+            .take_while(|&&b| b == 0)
+            .count();
+        i += zeros;
+        // Literals end where a run of zeros long enough to be worth a new pair starts
+        let start = i;
+        while i < data.len() && i - start < u16::MAX as usize {
+            if data[i..].iter().take(4).all(|&b| b == 0) && data.len() - i >= 4 {
+                break;
+            }
+            i += 1;
+        }
+        out.extend_from_slice(&(zeros as u16).to_le_bytes());
+        out.extend_from_slice(&((i - start) as u16).to_le_bytes());
+        out.extend_from_slice(&data[start..i]);
+    }
+    out
+}
+
+fn decompress(data: &[u8], len: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i + 4 <= data.len() {
+        let zeros = u16::from_le_bytes([data[i], data[i + 1]]) as usize;
+        let literals = u16::from_le_bytes([data[i + 2], data[i + 3]]) as usize;
+        i += 4;
+        out.resize(out.len() + zeros, 0);
+        out.extend_from_slice(&data[i..i + literals]);
+        i += literals;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    fn round_trip(data: &[u8]) -> Vec<u8> {
+        let compressed = compress(data);
+        assert_eq!(decompress(&compressed, data.len()), data);
+        compressed
+    }
+
+    #[test]
+    fn xor_pads_a_short_base_with_zeros() {
+        assert_eq!(xor(&[0x0F, 0xF0, 0x55], &[0xFF, 0xF0]), [0xF0, 0x00, 0x55]);
+        assert_eq!(xor(&[0x12], &[0x12, 0x34]), [0x00]);
+    }
+
+    #[test]
+    fn compresses_runs_of_zeros() {
+        assert!(round_trip(&[]).is_empty());
+        assert_eq!(round_trip(&[0; 100]), [100, 0, 0, 0]);
+        assert_eq!(round_trip(&[1, 2]), [0, 0, 2, 0, 1, 2]);
+        assert_eq!(
+            round_trip(&[0, 0, 7, 0, 0, 0, 0, 0, 8]),
+            [2, 0, 1, 0, 7, 5, 0, 1, 0, 8]
+        );
+        // Short runs of zeros stay in the literals, and so do the last few bytes
+        assert_eq!(round_trip(&[7, 0, 0, 8, 0]), [0, 0, 5, 0, 7, 0, 0, 8, 0]);
+    }
+
+    #[test]
+    fn splits_counts_that_overflow_a_u16() {
+        let zeros = vec![0; 70_000];
+        assert_eq!(round_trip(&zeros).len(), 8);
+
+        let literals: Vec<u8> = (0..70_000u32).map(|i| i as u8 | 1).collect();
+        assert_eq!(round_trip(&literals).len(), 70_000 + 8);
+    }
+
+    #[test]
+    fn steps_back_through_keyframes_and_deltas() {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for frame in 1..=KEYFRAME_INTERVAL as u64 + 3 {
+            gameboy.frame_count = frame;
+            gameboy.mmu.poke(0xC000, frame as u8);
+            rewind.record(&gameboy);
+        }
+        assert_eq!(rewind.len(), KEYFRAME_INTERVAL + 3);
+        let keyframes = rewind.snapshots.iter().filter(|s| s.keyframe).count();
+        assert_eq!(keyframes, 2);
+
+        gameboy.frame_count += 1;
+        for frame in (1..=KEYFRAME_INTERVAL as u64 + 3).rev() {
+            assert!(rewind.step_back(&mut gameboy).unwrap());
+            assert_eq!(gameboy.frame_count, frame);
+            assert_eq!(gameboy.mmu.peek(0xC000), frame as u8);
+        }
+        assert!(!rewind.step_back(&mut gameboy).unwrap());
+        assert_eq!(rewind.memory(), 0);
+    }
+
+    #[test]
+    fn evicts_deltas_with_their_keyframe() {
+        let mut gameboy = GameBoy::new(Model::DMG);
+        let mut rewind = Rewind::new(1, usize::MAX);
+        for frame in 1..=KEYFRAME_INTERVAL as u64 + 3 {
+            gameboy.frame_count = frame;
+            rewind.record(&gameboy);
+        }
+        // Frames 1-31 are a keyframe and its deltas: too much to keep with 32 and 33
+        rewind.max_memory = rewind.memory() - 1;
+        rewind.evict();
+        assert_eq!(
+            rewind.frames().collect::<Vec<_>>(),
+            [KEYFRAME_INTERVAL as u64 + 2, KEYFRAME_INTERVAL as u64 + 3]
+        );
+        assert!(rewind.snapshots[0].keyframe);
+    }
+}
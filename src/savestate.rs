@@ -0,0 +1,291 @@
+use std::io;
+
+use crate::checksum::crc32;
+use crate::model::Model;
+
+/*
+Save state: a snapshot of the whole machine.
+
+Binary format, little-endian:
+    "RUSTBOY-STATE"   Magic
+    Version           u32. States from other versions are rejected
+    Model             Name, length-prefixed
+    ROM CRC-32        u32. The ROM itself isn't saved, it must be loaded already
+    Payload length    u32
+    Payload CRC-32    u32
+    Payload           CPU, MMU (memory, banking, joypad, serial, APU), PPU and frame counters
+
+The payload is checked before any of it is applied, and rolled back if it fails to load anyway,
+so a bad file leaves the machine untouched.
+*/
+
+const MAGIC: &[u8] = b"RUSTBOY-STATE";
+pub const VERSION: u32 = 3;
+
+/// State that goes into a save state. `load_state` reads back exactly what `save_state` wrote.
+pub trait SaveState {
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()>;
+}
+
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Fixed-size data: the reader must know the length.
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Variable-size data, length-prefixed.
+    pub fn vec(&mut self, bytes: &[u8]) {
+        self.u32(bytes.len() as u32);
+        self.bytes(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        StateReader { data }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    pub fn bytes(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if self.data.len() < len {
+            return Err(invalid("save state is truncated"));
+        }
+        let (bytes, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(bytes)
+    }
+
+    fn array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        Ok(self.bytes(N)?.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> io::Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.array()?))
+    }
+
+    pub fn i32(&mut self) -> io::Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+
+    pub fn vec(&mut self) -> io::Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.bytes(len)?.to_vec())
+    }
+
+    /// Copies into a fixed-size buffer, as written by `StateWriter::bytes`.
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> io::Result<()> {
+        buffer.copy_from_slice(self.bytes(buffer.len())?);
+        Ok(())
+    }
+}
+
+pub fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Wraps a payload in the header.
+pub fn encode(model: Model, rom_crc32: u32, payload: &[u8]) -> Vec<u8> {
+    let mut w = StateWriter::new();
+    w.bytes(MAGIC);
+    w.u32(VERSION);
+    w.vec(model.name().as_bytes());
+    w.u32(rom_crc32);
+    w.u32(payload.len() as u32);
+    w.u32(crc32(payload));
+    w.bytes(payload);
+    w.into_bytes()
+}
+
+/// Checks the header against the running machine and returns the payload.
+pub fn decode(data: &[u8], model: Model, rom_crc32: u32) -> io::Result<&[u8]> {
+    let mut r = StateReader::new(data);
+    if r.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+        return Err(invalid("not a save state"));
+    }
+    let version = r.u32()?;
+    if version != VERSION {
+        return Err(invalid(format!(
+            "save state version {} is not supported (expected {})",
+            version, VERSION
+        )));
+    }
+    let state_model = String::from_utf8_lossy(&r.vec()?).into_owned();
+    if state_model != model.name() {
+        return Err(invalid(format!(
+            "save state is for model {}, running {}",
+            state_model, model
+        )));
+    }
+    let state_crc32 = r.u32()?;
+    if state_crc32 != rom_crc32 {
+        return Err(invalid(format!(
+            "save state is for ROM {:08X}, running {:08X}",
+            state_crc32, rom_crc32
+        )));
+    }
+    let len = r.u32()? as usize;
+    let payload_crc32 = r.u32()?;
+    let payload = r.bytes(len)?;
+    if crc32(payload) != payload_crc32 || !r.is_empty() {
+        return Err(invalid("save state is corrupt"));
+    }
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CRC: u32 = 0x1A2B_3C4D;
+
+    fn error(result: io::Result<&[u8]>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789A_BCDE);
+        w.u64(u64::MAX - 1);
+        w.i32(-2);
+        w.bytes(&[1, 2, 3]);
+        w.vec(&[4, 5]);
+        let data = w.into_bytes();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8().unwrap(), 0x12);
+        assert!(r.bool().unwrap());
+        assert_eq!(r.u16().unwrap(), 0x3456);
+        assert_eq!(r.u32().unwrap(), 0x789A_BCDE);
+        assert_eq!(r.u64().unwrap(), u64::MAX - 1);
+        assert_eq!(r.i32().unwrap(), -2);
+        let mut buffer = [0; 3];
+        r.read_into(&mut buffer).unwrap();
+        assert_eq!(buffer, [1, 2, 3]);
+        assert_eq!(r.vec().unwrap(), [4, 5]);
+        assert!(r.is_empty());
+        assert!(r.u8().is_err());
+    }
+
+    #[test]
+    fn payload_round_trips() {
+        let state = encode(Model::CGB, CRC, b"payload");
+        assert_eq!(decode(&state, Model::CGB, CRC).unwrap(), b"payload");
+    }
+
+    #[test]
+    fn rejects_states_for_another_machine() {
+        let state = encode(Model::CGB, CRC, b"payload");
+        assert_eq!(
+            error(decode(&state, Model::DMG, CRC)),
+            "save state is for model cgb, running dmg"
+        );
+        assert_eq!(
+            error(decode(&state, Model::CGB, 0)),
+            "save state is for ROM 1A2B3C4D, running 00000000"
+        );
+        assert_eq!(
+            error(decode(b"RUSTBOY-MOVIE", Model::CGB, CRC)),
+            "not a save state"
+        );
+
+        let mut old = state.clone();
+        old[MAGIC.len()] = 1;
+        assert_eq!(
+            error(decode(&old, Model::CGB, CRC)),
+            format!(
+                "save state version 1 is not supported (expected {})",
+                VERSION
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_corrupt_states() {
+        let state = encode(Model::DMG, CRC, b"payload");
+
+        let mut flipped = state.clone();
+        *flipped.last_mut().unwrap() ^= 0x01;
+        assert_eq!(
+            error(decode(&flipped, Model::DMG, CRC)),
+            "save state is corrupt"
+        );
+
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(
+            error(decode(&longer, Model::DMG, CRC)),
+            "save state is corrupt"
+        );
+
+        let truncated = &state[..state.len() - 1];
+        assert_eq!(
+            error(decode(truncated, Model::DMG, CRC)),
+            "save state is truncated"
+        );
+    }
+}
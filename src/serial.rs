@@ -0,0 +1,420 @@
+pub mod link;
+
+use std::io;
+
+pub use self::link::{Link, Packet};
+
+use crate::savestate::{SaveState, StateReader, StateWriter};
+
+pub const SB: u16 = 0xFF01; // Serial transfer data
+pub const SC: u16 = 0xFF02;
+/*
+SC: Serial transfer control
+bit 7: Transfer enable. Set to start, cleared by the hardware when the byte is done
+bit 6-2: not used, read as 1
+bit 1: Clock speed, CGB only (1=Fast, 262144 Hz)
+bit 0: Clock select (0=External, from the other Game Boy. 1=Internal, 8192 Hz)
+*/
+
+const TRANSFER_ENABLE: u8 = 0x80;
+const FAST_CLOCK: u8 = 0x02;
+const INTERNAL_CLOCK: u8 = 0x01;
+
+const BIT_CYCLES: u32 = 128; // M-cycles per bit with the 8192 Hz internal clock
+const FAST_BIT_CYCLES: u32 = 4;
+const POLL_CYCLES: u32 = 128; // How often the link is checked for packets
+
+/// Receives every byte the Game Boy sends out of the serial port.
+pub trait SerialSink {
+    fn transmit(&mut self, byte: u8);
+    /// Bytes kept so far. Sinks that don't keep them have nothing to show.
+    fn output(&self) -> &[u8] {
+        &[]
+    }
+}
+
+/// Default sink: keeps everything, e.g. the "Passed"/"Failed" text of test ROMs.
+#[derive(Default)]
+pub struct SerialBuffer {
+    pub bytes: Vec<u8>,
+}
+
+impl SerialSink for SerialBuffer {
+    fn transmit(&mut self, byte: u8) {
+        self.bytes.push(byte);
+    }
+
+    fn output(&self) -> &[u8] {
+        &self.bytes
+    }
+}
+
+/// Serial port. Bytes go out one bit at a time; the other side is a `Link`, or nothing.
+pub struct Serial {
+    pub sb: u8,
+    pub sc: u8,
+    cgb: bool,
+    bits_left: u8,
+    timer: u32,           // M-cycles until the next bit is shifted
+    incoming: Option<u8>, // The peer's byte for the current transfer
+    sent: u8,             // SB when the transfer started
+    send_pending: bool,   // The transfer started but the peer doesn't know yet
+    poll_timer: u32,
+    link: Option<Box<dyn Link>>,
+}
+
+impl Serial {
+    pub fn new(cgb: bool) -> Self {
+        Serial {
+            sb: 0,
+            sc: 0,
+            cgb,
+            bits_left: 0,
+            timer: 0,
+            incoming: None,
+            sent: 0,
+            send_pending: false,
+            poll_timer: 0,
+            link: None,
+        }
+    }
+
+    pub fn handles(address: u16) -> bool {
+        address == SB || address == SC
+    }
+
+    /// Plugs the cable in. Without a link, nothing drives the line and bytes read as 0xFF.
+    pub fn set_link(&mut self, link: Box<dyn Link>) {
+        self.link = Some(link);
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            SB => self.sb,
+            _ if self.cgb => self.sc | 0x7C,
+            _ => self.sc | 0x7E,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if address == SB {
+            self.sb = value;
+            return;
+        }
+        let mask = if self.cgb { 0x83 } else { 0x81 };
+        self.sc = value & mask;
+        if self.sc & TRANSFER_ENABLE == 0 {
+            self.bits_left = 0;
+            self.send_pending = false;
+        } else if self.sc & INTERNAL_CLOCK != 0 {
+            self.start_transfer();
+        }
+    }
+
+    // Internal clock: our byte goes out on the next step, the peer answers with theirs
+    fn start_transfer(&mut self) {
+        self.bits_left = 8;
+        self.timer = self.bit_cycles();
+        self.sent = self.sb;
+        if self.link.is_some() {
+            self.incoming = None;
+            self.send_pending = true;
+        } else {
+            self.incoming = Some(0xFF);
+        }
+    }
+
+    fn bit_cycles(&self) -> u32 {
+        if self.cgb && self.sc & FAST_CLOCK != 0 {
+            FAST_BIT_CYCLES
+        } else {
+            BIT_CYCLES
+        }
+    }
+
+    /// Advances the port by `cycles` M-cycles. Returns the byte sent when a transfer completes,
+    /// which also requests the Serial interrupt.
+    pub fn step(&mut self, cycles: u32) -> io::Result<Option<u8>> {
+        let mut completed = None;
+
+        if self.send_pending {
+            self.send_pending = false;
+            if let Some(link) = self.link.as_mut() {
+                if let Err(e) = link.send(Packet::Transfer(self.sent)) {
+                    self.disconnect(e)?;
+                }
+            }
+        }
+        if self.link.is_some() {
+            self.poll_timer += cycles;
+            if self.poll_timer >= POLL_CYCLES {
+                self.poll_timer = 0;
+                completed = self.poll_link()?;
+            }
+        }
+
+        if self.bits_left == 0 || self.sc & INTERNAL_CLOCK == 0 {
+            return Ok(completed);
+        }
+        let mut cycles = cycles;
+        while cycles > 0 && self.bits_left > 0 {
+            let elapsed = cycles.min(self.timer);
+            self.timer -= elapsed;
+            cycles -= elapsed;
+            if self.timer > 0 {
+                break;
+            }
+            // The last bit waits for the peer's answer, however long it takes to arrive
+            if self.bits_left == 1 && self.incoming.is_none() {
+                self.timer = 1;
+                break;
+            }
+            self.shift_bit();
+            self.timer = self.bit_cycles();
+            if self.bits_left == 0 {
+                completed = Some(self.sent);
+            }
+        }
+        Ok(completed)
+    }
+
+    // MSB first out; the peer's bits come in at the bottom
+    fn shift_bit(&mut self) {
+        self.bits_left -= 1;
+        let bit = self.incoming.map_or(1, |byte| (byte >> self.bits_left) & 1);
+        self.sb = (self.sb << 1) | bit;
+        if self.bits_left == 0 {
+            // Bits shifted before the answer arrived were guesses
+            self.sb = self.incoming.unwrap_or(0xFF);
+            self.sc &= !TRANSFER_ENABLE;
+        }
+    }
+
+    fn poll_link(&mut self) -> io::Result<Option<u8>> {
+        let Some(link) = self.link.as_mut() else {
+            return Ok(None);
+        };
+        let packet = match link.receive() {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return Ok(None),
+            Err(e) => {
+                self.disconnect(e)?;
+                return Ok(None);
+            }
+        };
+
+        match packet {
+            Packet::Reply(byte) => {
+                if self.bits_left > 0 && self.incoming.is_none() {
+                    self.incoming = Some(byte);
+                }
+                Ok(None)
+            }
+            Packet::Transfer(byte) => {
+                // The peer clocks the whole byte at once. Only a port waiting on the external
+                // clock takes part, otherwise the peer reads an idle line
+                let waiting = self.sc & TRANSFER_ENABLE != 0 && self.sc & INTERNAL_CLOCK == 0;
+                let reply = if waiting { self.sb } else { 0xFF };
+                if let Err(e) = link.send(Packet::Reply(reply)) {
+                    self.disconnect(e)?;
+                }
+                if !waiting {
+                    return Ok(None);
+                }
+                self.sb = byte;
+                self.sc &= !TRANSFER_ENABLE;
+                Ok(Some(reply))
+            }
+        }
+    }
+
+    // A closed connection is an unplugged cable. Anything else is a real error
+    fn disconnect(&mut self, error: io::Error) -> io::Result<()> {
+        self.link = None;
+        if self.bits_left > 0 && self.incoming.is_none() {
+            self.incoming = Some(0xFF);
+        }
+        match error.kind() {
+            io::ErrorKind::UnexpectedEof
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::BrokenPipe => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new(false)
+    }
+}
+
+// The link itself isn't state: a state loaded with the cable plugged in keeps it plugged in
+impl SaveState for Serial {
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.sb);
+        w.u8(self.sc);
+        w.u8(self.bits_left);
+        w.u32(self.timer);
+        w.bool(self.incoming.is_some());
+        w.u8(self.incoming.unwrap_or(0));
+        w.u8(self.sent);
+        w.bool(self.send_pending);
+        w.u32(self.poll_timer);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> io::Result<()> {
+        self.sb = r.u8()?;
+        self.sc = r.u8()?;
+        self.bits_left = r.u8()?;
+        self.timer = r.u32()?;
+        let has_incoming = r.bool()?;
+        let incoming = r.u8()?;
+        self.incoming = has_incoming.then_some(incoming);
+        self.sent = r.u8()?;
+        self.send_pending = r.bool()?;
+        self.poll_timer = r.u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mmu::MMU;
+    use crate::model::Model;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    type Wire = Rc<RefCell<VecDeque<Packet>>>;
+
+    // One end of a cable made of two queues
+    struct WireLink {
+        outgoing: Wire,
+        incoming: Wire,
+    }
+
+    impl Link for WireLink {
+        fn send(&mut self, packet: Packet) -> io::Result<()> {
+            self.outgoing.borrow_mut().push_back(packet);
+            Ok(())
+        }
+
+        fn receive(&mut self) -> io::Result<Option<Packet>> {
+            Ok(self.incoming.borrow_mut().pop_front())
+        }
+    }
+
+    fn cable() -> (Box<dyn Link>, Box<dyn Link>) {
+        let (a, b) = (Wire::default(), Wire::default());
+        let left = WireLink {
+            outgoing: a.clone(),
+            incoming: b.clone(),
+        };
+        let right = WireLink {
+            outgoing: b,
+            incoming: a,
+        };
+        (Box::new(left), Box::new(right))
+    }
+
+    // Steps one M-cycle at a time, returning how long the transfer took and what it sent
+    fn transfer(serial: &mut Serial) -> (u32, u8) {
+        for cycles in 1..=10_000 {
+            if let Some(byte) = serial.step(1).unwrap() {
+                return (cycles, byte);
+            }
+        }
+        panic!("the transfer never finished");
+    }
+
+    #[test]
+    fn shifts_a_bit_every_128_cycles() {
+        let mut serial = Serial::new(false);
+        serial.write(SB, 0x55);
+        serial.write(SC, 0x81);
+        assert_eq!(serial.read(SC), 0xFF);
+        assert_eq!(transfer(&mut serial), (8 * BIT_CYCLES, 0x55));
+        assert_eq!(serial.sb, 0xFF); // Nobody on the other end
+        assert_eq!(serial.read(SC), 0x7F);
+    }
+
+    #[test]
+    fn cgb_fast_clock_shifts_a_bit_every_4_cycles() {
+        let mut serial = Serial::new(true);
+        serial.write(SC, 0x83);
+        assert_eq!(transfer(&mut serial).0, 8 * FAST_BIT_CYCLES);
+
+        // No fast clock on DMG
+        let mut serial = Serial::new(false);
+        serial.write(SC, 0x83);
+        assert_eq!(serial.read(SC), 0xFF);
+        assert_eq!(transfer(&mut serial).0, 8 * BIT_CYCLES);
+    }
+
+    #[test]
+    fn external_clock_waits_for_the_peer() {
+        let mut serial = Serial::new(false);
+        serial.write(SC, 0x80);
+        assert_eq!(serial.step(100_000).unwrap(), None);
+        assert_eq!(serial.read(SC), 0xFE);
+    }
+
+    #[test]
+    fn completed_transfers_request_the_serial_interrupt() {
+        let mut mmu = MMU::new(Model::DMG);
+        mmu.write_byte(0xFF0F, 0x00);
+        mmu.write_byte(SB, b'A');
+        mmu.write_byte(SC, 0x81);
+        mmu.step_serial(8 * BIT_CYCLES - 1).unwrap();
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x08, 0);
+        mmu.step_serial(1).unwrap();
+        assert_eq!(mmu.read_byte(0xFF0F) & 0x08, 0x08);
+        assert_eq!(mmu.serial_sink.output(), b"A");
+    }
+
+    #[test]
+    fn bytes_cross_the_cable_both_ways() {
+        let (left, right) = cable();
+        let mut master = Serial::new(false);
+        let mut slave = Serial::new(false);
+        master.set_link(left);
+        slave.set_link(right);
+
+        slave.write(SB, 0x34);
+        slave.write(SC, 0x80);
+        master.write(SB, 0x12);
+        master.write(SC, 0x81);
+
+        let mut done = (None, None);
+        for _ in 0..10_000 {
+            done.0 = done.0.or(master.step(1).unwrap());
+            done.1 = done.1.or(slave.step(1).unwrap());
+        }
+        // Each side reports the byte it sent, and ends up with the other's
+        assert_eq!(done, (Some(0x12), Some(0x34)));
+        assert_eq!((master.sb, slave.sb), (0x34, 0x12));
+        assert_eq!(master.sc & TRANSFER_ENABLE, 0);
+        assert_eq!(slave.sc & TRANSFER_ENABLE, 0);
+    }
+
+    #[test]
+    fn an_idle_peer_answers_0xff() {
+        let (left, right) = cable();
+        let mut master = Serial::new(false);
+        let mut peer = Serial::new(false);
+        master.set_link(left);
+        peer.set_link(right);
+
+        master.write(SB, 0x12);
+        master.write(SC, 0x81);
+        for _ in 0..10_000 {
+            master.step(1).unwrap();
+            peer.step(1).unwrap();
+        }
+        assert_eq!(master.sb, 0xFF);
+        assert_eq!(peer.sb, 0x00); // Not waiting for a byte, so it keeps its own
+    }
+}
@@ -0,0 +1,222 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+const TRANSFER: u8 = 0x01;
+const REPLY: u8 = 0x02;
+
+/// What goes over the cable: the clocking side sends its byte, the other side answers with its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Packet {
+    Transfer(u8),
+    Reply(u8),
+}
+
+/// The other end of the link cable.
+pub trait Link {
+    fn send(&mut self, packet: Packet) -> io::Result<()>;
+    /// Next packet from the peer, if one has arrived. Must not block.
+    fn receive(&mut self) -> io::Result<Option<Packet>>;
+}
+
+/// Link over a non-blocking socket. Packets are two bytes: kind and value.
+pub struct StreamLink<S: Read + Write> {
+    stream: S,
+    buffer: Vec<u8>,
+    outgoing: Vec<u8>, // Bytes the socket had no room for yet
+}
+
+impl<S: Read + Write> StreamLink<S> {
+    fn new(stream: S) -> Self {
+        StreamLink {
+            stream,
+            buffer: Vec::new(),
+            outgoing: Vec::new(),
+        }
+    }
+
+    // Writes what the socket takes now. The rest waits for the next send or receive
+    fn write_outgoing(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.outgoing.drain(..n);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        match self.stream.flush() {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(()),
+            result => result,
+        }
+    }
+}
+
+impl<S: Read + Write> Link for StreamLink<S> {
+    fn send(&mut self, packet: Packet) -> io::Result<()> {
+        let bytes = match packet {
+            Packet::Transfer(byte) => [TRANSFER, byte],
+            Packet::Reply(byte) => [REPLY, byte],
+        };
+        self.outgoing.extend_from_slice(&bytes);
+        self.write_outgoing()
+    }
+
+    fn receive(&mut self) -> io::Result<Option<Packet>> {
+        self.write_outgoing()?;
+        let mut bytes = [0; 64];
+        while self.buffer.len() < 2 {
+            match self.stream.read(&mut bytes) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => self.buffer.extend_from_slice(&bytes[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let packet = match self.buffer[0] {
+            TRANSFER => Packet::Transfer(self.buffer[1]),
+            REPLY => Packet::Reply(self.buffer[1]),
+            kind => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("unknown link packet 0x{:02X}", kind),
+                ))
+            }
+        };
+        self.buffer.drain(..2);
+        Ok(Some(packet))
+    }
+}
+
+/// Waits for the other emulator to connect. `address` is "host:port", or "unix:<path>".
+pub fn listen(address: &str) -> io::Result<Box<dyn Link>> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        let listener = UnixListener::bind(path)?;
+        let stream = listener.accept().map(|(stream, _)| stream);
+        // Nobody else can connect now, the socket file is no longer needed
+        std::fs::remove_file(path)?;
+        let stream = stream?;
+        stream.set_nonblocking(true)?;
+        return Ok(Box::new(StreamLink::new(stream)));
+    }
+    let (stream, _) = TcpListener::bind(address)?.accept()?;
+    tcp_link(stream)
+}
+
+/// Connects to an emulator started with `listen`.
+pub fn connect(address: &str) -> io::Result<Box<dyn Link>> {
+    #[cfg(unix)]
+    if let Some(path) = address.strip_prefix("unix:") {
+        let stream = UnixStream::connect(path)?;
+        stream.set_nonblocking(true)?;
+        return Ok(Box::new(StreamLink::new(stream)));
+    }
+    tcp_link(TcpStream::connect(address)?)
+}
+
+fn tcp_link(stream: TcpStream) -> io::Result<Box<dyn Link>> {
+    // Every packet waits for an answer, so don't let Nagle hold them back
+    stream.set_nodelay(true)?;
+    stream.set_nonblocking(true)?;
+    Ok(Box::new(StreamLink::new(stream)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    // Socket that has `room` bytes of buffer space left and delivers `input` in `chunk`s
+    struct FakeStream {
+        input: VecDeque<u8>,
+        chunk: usize,
+        output: Vec<u8>,
+        room: usize,
+    }
+
+    impl FakeStream {
+        fn new(input: &[u8], room: usize) -> Self {
+            FakeStream {
+                input: input.iter().copied().collect(),
+                chunk: 64,
+                output: Vec::new(),
+                room,
+            }
+        }
+    }
+
+    impl Read for FakeStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.input.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.chunk).min(self.input.len());
+            for byte in buf.iter_mut().take(n) {
+                *byte = self.input.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for FakeStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let n = buf.len().min(self.room);
+            self.room -= n;
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn frames_packets_as_kind_and_value() {
+        let mut link = StreamLink::new(FakeStream::new(&[], 64));
+        link.send(Packet::Transfer(0x12)).unwrap();
+        link.send(Packet::Reply(0xFF)).unwrap();
+        assert_eq!(link.stream.output, [TRANSFER, 0x12, REPLY, 0xFF]);
+    }
+
+    #[test]
+    fn reassembles_packets_split_across_reads() {
+        let mut stream = FakeStream::new(&[TRANSFER, 0x12, REPLY, 0x34, REPLY], 64);
+        stream.chunk = 1;
+        let mut link = StreamLink::new(stream);
+        assert_eq!(link.receive().unwrap(), Some(Packet::Transfer(0x12)));
+        assert_eq!(link.receive().unwrap(), Some(Packet::Reply(0x34)));
+        assert_eq!(link.receive().unwrap(), None); // Half a packet so far
+        link.stream.input.push_back(0x56);
+        assert_eq!(link.receive().unwrap(), Some(Packet::Reply(0x56)));
+    }
+
+    #[test]
+    fn rejects_unknown_packets() {
+        let mut link = StreamLink::new(FakeStream::new(&[0x07, 0x00], 64));
+        let error = link.receive().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "unknown link packet 0x07");
+    }
+
+    #[test]
+    fn a_full_socket_keeps_the_packet_for_later() {
+        let mut link = StreamLink::new(FakeStream::new(&[], 1));
+        link.send(Packet::Transfer(0x12)).unwrap();
+        assert_eq!(link.stream.output, [TRANSFER]);
+
+        link.stream.room = 64;
+        assert_eq!(link.receive().unwrap(), None);
+        assert_eq!(link.stream.output, [TRANSFER, 0x12]);
+        assert!(link.outgoing.is_empty());
+    }
+}
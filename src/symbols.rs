@@ -0,0 +1,159 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/*
+RGBDS symbol file, as written by `rgblink -n`. One label per line, bank and address in hex:
+    ; File generated by rgblink
+    00:0150 Main
+    00:0153 Main.loop
+    01:4000 Tables
+    00:c000 wBuffer
+Anything after a ';' is a comment. Banks are the ROM bank for 0x4000-0x7FFF and the bank of
+whatever memory the label is in elsewhere, 00 for unbanked memory.
+*/
+
+// Memory areas a label can cover: `Main+$10` must not reach into the next one
+const REGIONS: [u16; 10] = [
+    0x0000, 0x4000, 0x8000, 0xA000, 0xC000, 0xD000, 0xE000, 0xFE00, 0xFF00, 0xFF80,
+];
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    by_address: BTreeMap<(u16, u16), String>, // (bank, address)
+    by_name: HashMap<String, (u16, u16)>,
+}
+
+impl Symbols {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut symbols = Symbols::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let invalid = || format!("line {}: expected 'bank:address label'", i + 1);
+            let (location, name) = line.split_once(char::is_whitespace).ok_or_else(invalid)?;
+            let (bank, address) = location.split_once(':').ok_or_else(invalid)?;
+            let bank = u16::from_str_radix(bank, 16).map_err(|_| invalid())?;
+            let address = u16::from_str_radix(address, 16).map_err(|_| invalid())?;
+            symbols.insert(bank, address, name.trim());
+        }
+        Ok(symbols)
+    }
+
+    /// The first label loaded for an address, and the first address for a name, win.
+    pub fn insert(&mut self, bank: u16, address: u16, name: &str) {
+        self.by_address
+            .entry((bank, address))
+            .or_insert_with(|| name.to_string());
+        self.by_name
+            .entry(name.to_string())
+            .or_insert((bank, address));
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    /// Label exactly at `address` in `bank`.
+    pub fn label(&self, bank: u16, address: u16) -> Option<&str> {
+        self.by_address.get(&(bank, address)).map(String::as_str)
+    }
+
+    /// Closest label at or before `address` in the same bank and memory area, as `Label` or
+    /// `Label+$N`.
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        let region = REGIONS.iter().rev().find(|&&start| start <= address)?;
+        let ((_, start), name) = self
+            .by_address
+            .range((bank, *region)..=(bank, address))
+            .next_back()?;
+        Some(match address - start {
+            0 => name.clone(),
+            offset => format!("{}+${:X}", name, offset),
+        })
+    }
+
+    /// Bank and address of a label. Names are case sensitive, as in RGBDS.
+    pub fn lookup(&self, name: &str) -> Option<(u16, u16)> {
+        self.by_name.get(name).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "; File generated by rgblink
+00:0150 Main
+00:0153 Main.loop ; local
+01:4000 Tables
+02:4000 Music
+
+00:7fff LastByte
+00:c000 wBuffer
+00:ff80 hCounter
+00:0150 Duplicate
+";
+
+    #[test]
+    fn parses_rgbds_symbol_files() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(symbols.len(), 8);
+        assert_eq!(symbols.label(0, 0x0150), Some("Main")); // First label wins
+        assert_eq!(symbols.label(0, 0x0153), Some("Main.loop"));
+        assert_eq!(symbols.lookup("Music"), Some((2, 0x4000)));
+        assert_eq!(symbols.lookup("Duplicate"), Some((0, 0x0150)));
+        assert_eq!(symbols.lookup("main"), None);
+    }
+
+    #[test]
+    fn rejects_bad_lines() {
+        let error = "line 2: expected 'bank:address label'";
+        assert_eq!(Symbols::parse("00:0150 Main\nMain").unwrap_err(), error);
+        assert_eq!(Symbols::parse("\n0150 Main").unwrap_err(), error);
+        assert_eq!(Symbols::parse("\n00:10000 Main").unwrap_err(), error);
+    }
+
+    #[test]
+    fn describes_addresses_from_the_closest_label() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(symbols.describe(0, 0x0153).as_deref(), Some("Main.loop"));
+        assert_eq!(symbols.describe(0, 0x0160).as_deref(), Some("Main.loop+$D"));
+        assert_eq!(symbols.describe(0, 0x014F), None);
+        // Banks don't mix
+        assert_eq!(symbols.describe(1, 0x4010).as_deref(), Some("Tables+$10"));
+        assert_eq!(symbols.describe(2, 0x4010).as_deref(), Some("Music+$10"));
+        assert_eq!(symbols.describe(3, 0x4010), None);
+    }
+
+    #[test]
+    fn labels_stop_at_region_boundaries() {
+        let symbols = Symbols::parse(FILE).unwrap();
+        assert_eq!(
+            symbols.describe(0, 0x3FFF).as_deref(),
+            Some("Main.loop+$3EAC")
+        );
+        assert_eq!(symbols.describe(0, 0x4000), None);
+        assert_eq!(symbols.describe(0, 0x7FFF).as_deref(), Some("LastByte"));
+        assert_eq!(symbols.describe(0, 0x8000), None); // Not ROM any more
+        assert_eq!(symbols.describe(0, 0xCFFF).as_deref(), Some("wBuffer+$FFF"));
+        assert_eq!(symbols.describe(0, 0xD000), None);
+        assert_eq!(symbols.describe(0, 0xFFFF).as_deref(), Some("hCounter+$7F"));
+    }
+}
@@ -0,0 +1,166 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::gpu::{tile_color, GPUControlRegisters};
+use crate::mmu::MMU;
+use crate::png;
+
+/*
+Tile sheet: the 384 tiles of 0x8000-0x97FF, 16 across and 24 down, in VRAM order. Rows 0-7 are
+the 0x8000 block, 8-15 the 0x8800 block and 16-23 the 0x9000 block. On CGB the tiles of VRAM
+bank 1 follow on the right, for a sheet twice as wide.
+*/
+
+const TILE_DATA: u16 = 0x8000;
+const TILE_COUNT: u16 = 384;
+const TILE_LENGTH: u16 = 16; // 8 rows of 2 bytes
+const TILES_ACROSS: usize = 16;
+const TILES_DOWN: usize = TILE_COUNT as usize / TILES_ACROSS;
+
+const GREY: [[u8; 3]; 4] = [[0xFF; 3], [0xAA; 3], [0x55; 3], [0x00; 3]];
+const GREEN: [[u8; 3]; 4] = [
+    [0x9B, 0xBC, 0x0F],
+    [0x8B, 0xAC, 0x0F],
+    [0x30, 0x62, 0x30],
+    [0x0F, 0x38, 0x0F],
+];
+
+/// Colors for the tile sheet. Grey and green show color indexes as they are, the others go
+/// through a palette register first, as the PPU would.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Palette {
+    #[default]
+    Grey,
+    Green,
+    Bgp,
+    Obp0,
+    Obp1,
+}
+
+impl Palette {
+    fn colors(self, mmu: &MMU) -> [[u8; 3]; 4] {
+        let register = match self {
+            Palette::Grey => return GREY,
+            Palette::Green => return GREEN,
+            Palette::Bgp => GPUControlRegisters::BGP,
+            Palette::Obp0 => GPUControlRegisters::OBP0,
+            Palette::Obp1 => GPUControlRegisters::OBP1,
+        };
+        let shades = mmu.peek(register as u16);
+        std::array::from_fn(|color| GREY[(shades >> (color * 2) & 0x03) as usize])
+    }
+}
+
+impl FromStr for Palette {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, String> {
+        match text.to_ascii_lowercase().as_str() {
+            "grey" | "gray" => Ok(Palette::Grey),
+            "green" => Ok(Palette::Green),
+            "bgp" => Ok(Palette::Bgp),
+            "obp0" => Ok(Palette::Obp0),
+            "obp1" => Ok(Palette::Obp1),
+            _ => Err(format!(
+                "unknown palette '{}', expected grey, green, bgp, obp0 or obp1",
+                text
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Palette {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Palette::Grey => "grey",
+            Palette::Green => "green",
+            Palette::Bgp => "bgp",
+            Palette::Obp0 => "obp0",
+            Palette::Obp1 => "obp1",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Renders the tile sheet: width, height and pixels row by row.
+pub fn render(mmu: &MMU, palette: Palette) -> (usize, usize, Vec<[u8; 3]>) {
+    let colors = palette.colors(mmu);
+    let banks = if mmu.model.is_cgb() { 2 } else { 1 };
+    let (width, height) = (TILES_ACROSS * 8 * banks, TILES_DOWN * 8);
+    let mut pixels = vec![colors[0]; width * height];
+
+    for tile in 0..TILE_COUNT as usize * banks {
+        let (bank, index) = (tile / TILE_COUNT as usize, tile % TILE_COUNT as usize);
+        let left = (bank * TILES_ACROSS + index % TILES_ACROSS) * 8;
+        let top = index / TILES_ACROSS * 8;
+        let address = TILE_DATA + index as u16 * TILE_LENGTH;
+        let byte = |address| mmu.peek_banked(bank as u16, address).unwrap_or_default();
+        for row in 0..8 {
+            let low = byte(address + row as u16 * 2);
+            let high = byte(address + row as u16 * 2 + 1);
+            for x in 0..8 {
+                let color = tile_color(low, high, x as u8);
+                pixels[(top + row) * width + left + x] = colors[color as usize];
+            }
+        }
+    }
+    (width, height, pixels)
+}
+
+/// Saves the tile sheet as a PNG.
+pub fn save<P: AsRef<Path>>(mmu: &MMU, palette: Palette, path: P) -> io::Result<()> {
+    let (width, height, pixels) = render(mmu, palette);
+    png::save(path, width as u32, height as u32, &pixels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // Rows of color indexes 0, 1, 2 and 3, two pixels each
+    const STRIPES: [u8; 2] = [0x33, 0x0F];
+
+    fn poke_tile(mmu: &mut MMU, bank: u16, tile: u16) {
+        let address = TILE_DATA + tile * TILE_LENGTH;
+        for i in 0..TILE_LENGTH {
+            assert!(mmu.poke_banked(bank, address + i, STRIPES[i as usize % 2]));
+        }
+    }
+
+    fn row(pixels: &[[u8; 3]], width: usize, x: usize, y: usize) -> &[[u8; 3]] {
+        &pixels[y * width + x..y * width + x + 8]
+    }
+
+    #[test]
+    fn renders_tiles_through_the_palette() {
+        let mut mmu = MMU::new(Model::DMG);
+        poke_tile(&mut mmu, 0, 17); // Second row of the sheet, second tile
+        let (width, height, pixels) = render(&mmu, Palette::Grey);
+        assert_eq!((width, height), (128, 192));
+        let [c0, c1, c2, c3] = GREY;
+        assert_eq!(row(&pixels, width, 8, 8), [c0, c0, c1, c1, c2, c2, c3, c3]);
+        assert_eq!(row(&pixels, width, 8, 15), [c0, c0, c1, c1, c2, c2, c3, c3]);
+        assert_eq!(row(&pixels, width, 0, 8), [c0; 8]);
+
+        mmu.poke(GPUControlRegisters::BGP as u16, 0x1B); // Reversed shades
+        let (_, _, pixels) = render(&mmu, Palette::Bgp);
+        assert_eq!(row(&pixels, width, 8, 8), [c3, c3, c2, c2, c1, c1, c0, c0]);
+    }
+
+    #[test]
+    fn cgb_sheet_shows_both_vram_banks() {
+        let mut mmu = MMU::new(Model::CGB);
+        poke_tile(&mut mmu, 1, 0);
+        let (width, height, pixels) = render(&mmu, Palette::Grey);
+        assert_eq!((width, height), (256, 192));
+        let [c0, c1, c2, c3] = GREY;
+        assert_eq!(row(&pixels, width, 0, 0), [c0; 8]);
+        assert_eq!(
+            row(&pixels, width, 128, 0),
+            [c0, c0, c1, c1, c2, c2, c3, c3]
+        );
+    }
+}
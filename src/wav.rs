@@ -0,0 +1,187 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::apu::{to_i16, Apu};
+
+const HEADER_LENGTH: u32 = 44;
+
+/// 16-bit PCM WAV file. Sizes in the header are patched in by `finish`, or on drop if it's
+/// never called, e.g. when an error cuts the recording short.
+pub struct WavWriter {
+    out: BufWriter<File>,
+    data_length: u32,
+    finished: bool,
+}
+
+impl WavWriter {
+    pub fn create<P: AsRef<Path>>(path: P, channels: u16, sample_rate: u32) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let block_align = channels * 2;
+
+        out.write_all(b"RIFF")?;
+        out.write_all(&(HEADER_LENGTH - 8).to_le_bytes())?; // Patched by `finish`
+        out.write_all(b"WAVE")?;
+        out.write_all(b"fmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        out.write_all(&1u16.to_le_bytes())?; // PCM
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&sample_rate.to_le_bytes())?;
+        out.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&16u16.to_le_bytes())?; // Bits per sample
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?; // Patched by `finish`
+
+        Ok(WavWriter {
+            out,
+            data_length: 0,
+            finished: false,
+        })
+    }
+
+    /// Writes interleaved samples, one per channel per frame.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        for sample in samples {
+            self.out.write_all(&sample.to_le_bytes())?;
+        }
+        self.data_length += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.finished = true;
+        self.write_lengths()
+    }
+
+    fn write_lengths(&mut self) -> io::Result<()> {
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(HEADER_LENGTH - 8 + self.data_length).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(HEADER_LENGTH as u64 - 4))?;
+        self.out.write_all(&self.data_length.to_le_bytes())?;
+        self.out.flush()
+    }
+}
+
+impl Drop for WavWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = self.write_lengths(); // Nobody to tell
+        }
+    }
+}
+
+/// Records the APU output to `<name>.wav`, and optionally every channel to `<name>-ch1.wav`..`-ch4.wav`.
+pub struct AudioRecorder {
+    mix: WavWriter,
+    channels: Option<[WavWriter; 4]>,
+}
+
+impl AudioRecorder {
+    /// Enables per-channel capture in the APU when `separate_channels` is set.
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        apu: &mut Apu,
+        separate_channels: bool,
+    ) -> io::Result<Self> {
+        let path = path.as_ref();
+        let sample_rate = apu.mixer.sample_rate();
+
+        let channels = if separate_channels {
+            apu.mixer.set_channel_capture(true);
+            Some([
+                WavWriter::create(channel_path(path, 1), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 2), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 3), 1, sample_rate)?,
+                WavWriter::create(channel_path(path, 4), 1, sample_rate)?,
+            ])
+        } else {
+            None
+        };
+
+        Ok(AudioRecorder {
+            mix: WavWriter::create(path, 2, sample_rate)?,
+            channels,
+        })
+    }
+
+    /// Writes the interleaved stereo mix taken from the APU, plus the channel tracks if recorded.
+    pub fn record(&mut self, stereo: &[f32], apu: &mut Apu) -> io::Result<()> {
+        let mix: Vec<i16> = stereo.iter().copied().map(to_i16).collect();
+        self.mix.write_samples(&mix)?;
+
+        if let Some(writers) = self.channels.as_mut() {
+            for (writer, samples) in writers.iter_mut().zip(apu.mixer.take_channel_samples()) {
+                let samples: Vec<i16> = samples.into_iter().map(to_i16).collect();
+                writer.write_samples(&samples)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.mix.finish()?;
+        if let Some(writers) = self.channels {
+            for writer in writers {
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn channel_path(path: &Path, channel: u8) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{}-ch{}.wav", stem, channel))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn read_u32(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn write(name: &str, finish: bool) -> Vec<u8> {
+        let path =
+            std::env::temp_dir().join(format!("rustboy-{}-{}.wav", std::process::id(), name));
+        let mut writer = WavWriter::create(&path, 2, 48000).unwrap();
+        writer.write_samples(&[1, -1, 0x1234, -0x1234]).unwrap();
+        if finish {
+            writer.finish().unwrap();
+        } else {
+            drop(writer);
+        }
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        data
+    }
+
+    fn check_header(data: &[u8]) {
+        assert_eq!(data.len(), HEADER_LENGTH as usize + 8);
+        assert_eq!(&data[0..4], b"RIFF");
+        assert_eq!(read_u32(data, 4), HEADER_LENGTH - 8 + 8);
+        assert_eq!(&data[8..16], b"WAVEfmt ");
+        assert_eq!(read_u32(data, 16), 16);
+        assert_eq!(&data[20..24], &[1, 0, 2, 0]); // PCM, stereo
+        assert_eq!(read_u32(data, 24), 48000);
+        assert_eq!(read_u32(data, 28), 48000 * 4);
+        assert_eq!(&data[32..36], &[4, 0, 16, 0]);
+        assert_eq!(&data[36..40], b"data");
+        assert_eq!(read_u32(data, 40), 8);
+        assert_eq!(&data[44..], &[1, 0, 0xFF, 0xFF, 0x34, 0x12, 0xCC, 0xED]);
+    }
+
+    #[test]
+    fn finish_writes_the_lengths() {
+        check_header(&write("finish", true));
+    }
+
+    #[test]
+    fn drop_writes_the_lengths() {
+        check_header(&write("drop", false));
+    }
+}
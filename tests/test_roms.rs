@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+// Runs a bundled test ROM headlessly, the way scripts do
+fn run(rom: &str, model: &str) -> Output {
+    let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    let rom = root.join("rom/test").join(rom);
+    // Tests run in parallel: one directory each
+    let name = rom.file_stem().unwrap().to_string_lossy();
+    let save_dir = std::env::temp_dir().join(format!("rustboy-{}-{}", std::process::id(), name));
+    let output = Command::new(env!("CARGO_BIN_EXE_Rustboy"))
+        .args([
+            "--headless",
+            "--frames",
+            "3000",
+            "--model",
+            model,
+            "--save-dir",
+        ])
+        .arg(&save_dir)
+        .arg(&rom)
+        .output()
+        .unwrap();
+    let _ = std::fs::remove_dir_all(&save_dir);
+    output
+}
+
+#[test]
+fn cartridge_ram_report_passes() {
+    let output = run("dmg_sound/rom_singles/01-registers.gb", "dmg");
+    assert_eq!(output.status.code(), Some(0));
+    let text = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        text.contains("01-registers") && text.contains("Passed"),
+        "{}",
+        text
+    );
+}
+
+#[test]
+fn cartridge_ram_report_fails() {
+    // Wave RAM reads while playing work on CGB, the DMG test expects them not to
+    let output = run("dmg_sound/rom_singles/09-wave read while on.gb", "cgb");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Failed"));
+}
+
+#[test]
+fn serial_report_passes() {
+    let output = run("cpu_instrs/individual/06-ld r,r.gb", "dmg");
+    assert_eq!(output.status.code(), Some(0));
+    assert!(String::from_utf8_lossy(&output.stdout).contains("Passed"));
+}
+
+#[test]
+fn headless_runs_leave_no_save_next_to_the_rom() {
+    let rom = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("rom/test/dmg_sound/rom_singles/01-registers.gb");
+    let status = Command::new(env!("CARGO_BIN_EXE_Rustboy"))
+        .arg("--headless")
+        .arg(&rom)
+        .output()
+        .unwrap()
+        .status;
+    assert!(status.success());
+    assert!(!rom.with_extension("sav").exists());
+}